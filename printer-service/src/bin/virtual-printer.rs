@@ -0,0 +1,109 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use akri_kubecon_demo::virtual_printer::{VirtualPrinter, PAPER_WIDTH};
+use clap::Parser;
+use clap_verbosity_flag::Verbosity;
+
+/// Virtual ESC/POS printer rendering every ticket it receives to a PNG file
+#[derive(Debug, Parser)]
+struct Cli {
+    #[command(flatten)]
+    verbose: Verbosity,
+    /// Where to listen for raw print jobs
+    #[arg(long, default_value = "0.0.0.0:9100")]
+    listen: String,
+    /// Render a captured job file instead of listening
+    #[arg(long)]
+    input: Option<PathBuf>,
+    /// Directory receiving the rendered tickets
+    #[arg(long, default_value = ".")]
+    output_dir: PathBuf,
+    /// Printable width in dots
+    #[arg(long, default_value_t = PAPER_WIDTH)]
+    paper_width: u32,
+    /// Report the paper as nearly exhausted
+    #[arg(long)]
+    paper_near_end: bool,
+    /// Report the paper as exhausted
+    #[arg(long)]
+    paper_out: bool,
+    /// Report the cover as open
+    #[arg(long)]
+    cover_open: bool,
+    /// Report an autocutter error
+    #[arg(long)]
+    cutter_error: bool,
+}
+
+fn serve(printer: &mut VirtualPrinter, mut stream: TcpStream) -> std::io::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            return Ok(());
+        }
+        printer.write_all(&buf[..read])?;
+        let mut replies = Vec::new();
+        printer.read_to_end(&mut replies)?;
+        stream.write_all(&replies)?;
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    env_logger::Builder::new()
+        .filter_level(cli.verbose.log_level_filter())
+        .init();
+
+    if let Err(e) = std::fs::create_dir_all(&cli.output_dir) {
+        log::error!("Unable to create {}: {}", cli.output_dir.display(), e);
+        return ExitCode::FAILURE;
+    }
+    let mut printer = VirtualPrinter::new(cli.paper_width, Some(cli.output_dir));
+    printer.status.paper_near_end = cli.paper_near_end;
+    printer.status.paper_out = cli.paper_out;
+    printer.status.cover_open = cli.cover_open;
+    printer.status.cutter_error = cli.cutter_error;
+
+    if let Some(input) = cli.input {
+        return match std::fs::read(&input) {
+            Ok(data) => {
+                printer.feed(&data);
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                log::error!("Unable to read {}: {}", input.display(), e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let listener = match TcpListener::bind(&cli.listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Unable to listen on {}: {}", cli.listen, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    log::info!("Listening on {}", cli.listen);
+    // Like a real printer, only one job connection is served at a time
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let peer = stream.peer_addr().ok();
+                log::info!("Connection from {:?}", peer);
+                if let Err(e) = serve(&mut printer, stream) {
+                    log::warn!("Connection from {:?} failed: {}", peer, e);
+                }
+            }
+            Err(e) => log::warn!("Unable to accept connection: {}", e),
+        }
+    }
+    ExitCode::SUCCESS
+}
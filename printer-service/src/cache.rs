@@ -0,0 +1,137 @@
+//! Images decoded, resized and rasterized once, until their file changes
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use akri_kubecon_demo::{
+    escpos::Bitmap,
+    raster::{self, Dithering},
+};
+use image::imageops::FilterType;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Image(#[from] image::ImageError),
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct Key {
+    path: PathBuf,
+    /// Bounding box the image is resized into
+    size: u32,
+    dithering: Dithering,
+}
+
+struct Entry {
+    /// Modification time and length of the file the bitmap was made from
+    version: (SystemTime, u64),
+    bitmap: Arc<Bitmap>,
+}
+
+#[derive(Default)]
+pub struct ImageCache {
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+impl ImageCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The image at `path` resized to fit in `size` by `size` dots and rasterized
+    pub fn get(
+        &self,
+        path: &Path,
+        size: u32,
+        dithering: Dithering,
+    ) -> Result<Arc<Bitmap>, CacheError> {
+        let key = Key {
+            path: path.to_owned(),
+            size,
+            dithering,
+        };
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                self.entries.lock().unwrap().remove(&key);
+                return Err(e.into());
+            }
+        };
+        let version = (metadata.modified()?, metadata.len());
+        if let Some(entry) = self.entries.lock().unwrap().get(&key) {
+            if entry.version == version {
+                return Ok(entry.bitmap.clone());
+            }
+        }
+
+        log::debug!("Rasterizing {} at {}", path.display(), size);
+        let image = image::open(path)?.resize(size, size, FilterType::Triangle);
+        let bitmap = Arc::new(raster::rasterize(&image, dithering));
+        self.entries.lock().unwrap().insert(
+            key,
+            Entry {
+                version,
+                bitmap: bitmap.clone(),
+            },
+        );
+        Ok(bitmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use image::{DynamicImage, GrayImage, Luma};
+
+    use super::*;
+
+    fn write_png(path: &Path, size: u32, level: u8) {
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(size, size, Luma([level])))
+            .save(path)
+            .unwrap();
+    }
+
+    #[test]
+    fn rebuilt_when_the_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("heart.png");
+        let cache = ImageCache::new();
+        write_png(&path, 16, 0);
+        let black = cache.get(&path, 8, Dithering::Threshold).unwrap();
+        assert_eq!((black.width, black.height), (8, 8));
+        assert!(Arc::ptr_eq(
+            &black,
+            &cache.get(&path, 8, Dithering::Threshold).unwrap()
+        ));
+
+        // A different length
+        write_png(&path, 32, 0);
+        let bigger = cache.get(&path, 8, Dithering::Threshold).unwrap();
+        assert!(!Arc::ptr_eq(&black, &bigger));
+
+        // Same length, only the modification time tells
+        let before = std::fs::metadata(&path).unwrap();
+        write_png(&path, 32, 255);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), before.len());
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(before.modified().unwrap() + Duration::from_secs(1))
+            .unwrap();
+        let white = cache.get(&path, 8, Dithering::Threshold).unwrap();
+        assert_ne!(white.data, bigger.data);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            cache.get(&path, 8, Dithering::Threshold),
+            Err(CacheError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+//! The icons users can pick, stored as `<name>.png` in a single directory
+//!
+//! Names come straight from URLs, so they are checked before ever being
+//! turned into a path and every file stays inside the icon directory.
+
+use std::{
+    io::{Cursor, ErrorKind},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use akri_kubecon_demo::{escpos::Bitmap, raster::Dithering};
+use axum::http::StatusCode;
+use image::{DynamicImage, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    cache::{CacheError, ImageCache},
+    files::write_atomic,
+};
+
+/// Printed size of the icons, in dots
+pub const ICON_SIZE: u32 = 256;
+const MAX_ICON_NAME_LEN: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum CatalogError {
+    #[error("Invalid icon name")]
+    InvalidName,
+    #[error("Icon not found")]
+    NotFound,
+    #[error("Invalid image: {0}")]
+    InvalidImage(image::ImageError),
+    #[error("Icon {0} cannot be decoded")]
+    Corrupted(String),
+    #[error("Icon storage error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl From<CatalogError> for (StatusCode, String) {
+    fn from(value: CatalogError) -> Self {
+        let code = match value {
+            CatalogError::InvalidName | CatalogError::InvalidImage(_) => StatusCode::BAD_REQUEST,
+            CatalogError::NotFound => StatusCode::NOT_FOUND,
+            CatalogError::Corrupted(_) | CatalogError::Io(_) => {
+                log::error!("{}", value);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (code, value.to_string())
+    }
+}
+
+/// Per icon settings, read from an optional `<icon>.json` next to the icon
+#[derive(Default, Serialize, Deserialize)]
+pub struct IconSettings {
+    #[serde(default)]
+    pub dithering: Dithering,
+}
+
+/// Icon names end up in file names, keep them to a safe subset
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ICON_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, image::ImageError> {
+    let mut buf = Cursor::new(Vec::new());
+    image.write_to(&mut buf, ImageOutputFormat::Png)?;
+    Ok(buf.into_inner())
+}
+
+fn not_found(e: std::io::Error) -> CatalogError {
+    match e.kind() {
+        ErrorKind::NotFound => CatalogError::NotFound,
+        _ => CatalogError::Io(e),
+    }
+}
+
+pub struct IconCatalog {
+    path: PathBuf,
+    cache: ImageCache,
+}
+
+impl IconCatalog {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            cache: ImageCache::new(),
+        }
+    }
+
+    fn file(&self, name: &str, extension: &str) -> Result<PathBuf, CatalogError> {
+        if !is_valid_name(name) {
+            return Err(CatalogError::InvalidName);
+        }
+        Ok(self.path.join(format!("{}.{}", name, extension)))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.file(name, "png").is_ok_and(|path| path.is_file())
+    }
+
+    /// Icons that can actually be printed, sorted by name
+    pub fn list(&self) -> Result<Vec<String>, CatalogError> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    log::warn!(
+                        "Skipping unreadable entry in {}: {}",
+                        self.path.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            if path.extension().and_then(|ext| ext.to_str()) != Some("png") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if !is_valid_name(name) {
+                log::debug!("Skipping icon with invalid name {}", path.display());
+                continue;
+            }
+            match self.bitmap(name, ICON_SIZE, self.settings(name).dithering) {
+                Ok(_) => names.push(name.to_owned()),
+                Err(e) => log::warn!("Skipping icon {}: {}", path.display(), e),
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// The stored PNG, as is
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, CatalogError> {
+        std::fs::read(self.file(name, "png")?).map_err(not_found)
+    }
+
+    /// The icon as printed at `size` dots, rasterized once until its file changes
+    pub fn bitmap(
+        &self,
+        name: &str,
+        size: u32,
+        dithering: Dithering,
+    ) -> Result<Arc<Bitmap>, CatalogError> {
+        match self.cache.get(&self.file(name, "png")?, size, dithering) {
+            Ok(bitmap) => Ok(bitmap),
+            Err(CacheError::Io(e)) => Err(not_found(e)),
+            Err(CacheError::Image(_)) => Err(CatalogError::Corrupted(name.to_owned())),
+        }
+    }
+
+    /// Settings of an icon, defaults being used when missing or invalid
+    pub fn settings(&self, name: &str) -> IconSettings {
+        let Ok(path) = self.file(name, "json") else {
+            return IconSettings::default();
+        };
+        match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid icon settings {}: {}", path.display(), e);
+                IconSettings::default()
+            }),
+            Err(_) => IconSettings::default(),
+        }
+    }
+
+    /// Store an icon, returning whether it is a new one
+    pub fn store(
+        &self,
+        name: &str,
+        image: &DynamicImage,
+        settings: Option<&IconSettings>,
+    ) -> Result<bool, CatalogError> {
+        let path = self.file(name, "png")?;
+        let png = encode_png(image).map_err(CatalogError::InvalidImage)?;
+        let created = !path.exists();
+        write_atomic(&path, &png)?;
+        if let Some(settings) = settings {
+            let settings = serde_json::to_vec(settings).map_err(std::io::Error::other)?;
+            write_atomic(&self.file(name, "json")?, &settings)?;
+        }
+        Ok(created)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), CatalogError> {
+        std::fs::remove_file(self.file(name, "png")?).map_err(not_found)?;
+        match std::fs::remove_file(self.file(name, "json")?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::*;
+
+    #[test]
+    fn valid_names() {
+        for name in [
+            "heart",
+            "kube-con_2024",
+            "A",
+            &"a".repeat(MAX_ICON_NAME_LEN),
+        ] {
+            assert!(is_valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_names() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "../heart",
+            "a/b",
+            "/etc/passwd",
+            "a\\b",
+            "%2e%2e",
+            "heart.png",
+            "heart ",
+            "cœur",
+            "❤",
+            &"a".repeat(MAX_ICON_NAME_LEN + 1),
+        ] {
+            assert!(!is_valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn paths_stay_in_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = IconCatalog::new(dir.path().to_owned());
+        assert_eq!(
+            catalog.file("heart", "png").unwrap(),
+            dir.path().join("heart.png")
+        );
+        for name in ["..", "../heart", "a/b", "a\\b", "%2e%2e", ""] {
+            assert!(matches!(
+                catalog.file(name, "png"),
+                Err(CatalogError::InvalidName)
+            ));
+            assert!(matches!(catalog.read(name), Err(CatalogError::InvalidName)));
+            assert!(matches!(
+                catalog.remove(name),
+                Err(CatalogError::InvalidName)
+            ));
+            assert!(!catalog.contains(name));
+        }
+    }
+
+    #[test]
+    fn list_skips_what_cannot_be_printed() {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = IconCatalog::new(dir.path().to_owned());
+        let icon = DynamicImage::ImageLuma8(GrayImage::from_pixel(16, 16, Luma([0])));
+        assert!(catalog.store("kube", &icon, None).unwrap());
+        assert!(!catalog.store("kube", &icon, None).unwrap());
+        catalog.store("akri", &icon, None).unwrap();
+        let png = encode_png(&icon).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"not an icon").unwrap();
+        std::fs::write(dir.path().join("broken.png"), b"not a png").unwrap();
+        std::fs::write(dir.path().join("bad name.png"), &png).unwrap();
+        std::fs::write(dir.path().join("a.b.png"), &png).unwrap();
+        std::fs::create_dir(dir.path().join("folder.png")).unwrap();
+
+        assert_eq!(catalog.list().unwrap(), ["akri", "kube"]);
+        assert!(matches!(
+            catalog.bitmap("broken", ICON_SIZE, Dithering::Threshold),
+            Err(CatalogError::Corrupted(_))
+        ));
+        assert!(matches!(
+            catalog.read("missing"),
+            Err(CatalogError::NotFound)
+        ));
+
+        catalog.remove("kube").unwrap();
+        assert_eq!(catalog.list().unwrap(), ["akri"]);
+    }
+}
@@ -0,0 +1,465 @@
+//! Settings differing from one kiosk to another, read from a TOML file
+//!
+//! Every key is optional, missing ones keep the wiring of the original
+//! machine. The command line and environment override the file for the
+//! most common settings, see `--help`.
+
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use akri_kubecon_demo::{
+    printer::{self, PrinterOptions},
+    transport::Device,
+};
+use rppal::spi::{Bus, SlaveSelect};
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+
+/// Highest BCM GPIO number on the 40 pin header
+const MAX_GPIO: u8 = 27;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Unable to read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid configuration {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn exe_dir_path(name: &str) -> PathBuf {
+    let mut path = std::env::current_exe().unwrap();
+    path.pop();
+    path.push(name);
+    path
+}
+
+/// Read next to the executable when no other file is given
+pub fn default_path() -> PathBuf {
+    exe_dir_path("love-machine.toml")
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the API listens on, its port is also advertised over mDNS
+    pub listen: SocketAddr,
+    pub icons_path: PathBuf,
+    /// Directory holding the ticket layouts
+    pub layouts_path: PathBuf,
+    /// Directory keeping the jobs waiting to be printed
+    pub spool_path: PathBuf,
+    /// Image printed beside the icon on heart pages
+    pub heart_path: PathBuf,
+    /// How long an idempotency key keeps returning its original job, in seconds
+    pub idempotency_window: u64,
+    pub printer: PrinterConfig,
+    pub buttons: ButtonsConfig,
+    pub displays: DisplaysConfig,
+    pub mdns: MdnsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            icons_path: exe_dir_path("icons"),
+            layouts_path: exe_dir_path("layouts"),
+            spool_path: exe_dir_path("spool"),
+            heart_path: exe_dir_path("heart.png"),
+            idempotency_window: 24 * 60 * 60,
+            printer: PrinterConfig::default(),
+            buttons: ButtonsConfig::default(),
+            displays: DisplaysConfig::default(),
+            mdns: MdnsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrinterConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub device: Device,
+    /// Printable width of the paper in dots
+    pub paper_width: u16,
+    /// Maximum rows sent in a single raster command
+    pub band_height: u16,
+    /// How long connecting or writing a chunk of a job may take, in seconds
+    pub write_timeout: u64,
+    /// Printed at the bottom of every ticket, nothing when empty
+    pub footer: String,
+}
+
+impl Default for PrinterConfig {
+    fn default() -> Self {
+        let options = PrinterOptions::default();
+        Self {
+            device: Device::Lp(PathBuf::from("/dev/usb/lp0")),
+            paper_width: options.paper_width,
+            band_height: options.band_height,
+            write_timeout: options.write_timeout.as_secs(),
+            footer: options.footer,
+        }
+    }
+}
+
+impl PrinterConfig {
+    pub fn options(&self) -> PrinterOptions {
+        PrinterOptions {
+            paper_width: self.paper_width,
+            band_height: self.band_height,
+            write_timeout: Duration::from_secs(self.write_timeout),
+            footer: self.footer.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// BCM GPIO numbers of the buttons
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ButtonsConfig {
+    pub key1: u8,
+    pub key2: u8,
+}
+
+impl Default for ButtonsConfig {
+    fn default() -> Self {
+        Self { key1: 25, key2: 26 }
+    }
+}
+
+/// Wiring of a single display, pins being BCM GPIO numbers
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub spi_bus: u8,
+    pub slave_select: u8,
+    pub dc: u8,
+    pub backlight: u8,
+    pub reset: u8,
+}
+
+impl DisplayConfig {
+    pub fn bus(&self) -> Result<Bus, ConfigError> {
+        match self.spi_bus {
+            0 => Ok(Bus::Spi0),
+            1 => Ok(Bus::Spi1),
+            2 => Ok(Bus::Spi2),
+            3 => Ok(Bus::Spi3),
+            4 => Ok(Bus::Spi4),
+            5 => Ok(Bus::Spi5),
+            6 => Ok(Bus::Spi6),
+            bus => Err(ConfigError::Invalid(format!("No SPI bus {}", bus))),
+        }
+    }
+
+    pub fn slave_select(&self) -> Result<SlaveSelect, ConfigError> {
+        match self.slave_select {
+            0 => Ok(SlaveSelect::Ss0),
+            1 => Ok(SlaveSelect::Ss1),
+            2 => Ok(SlaveSelect::Ss2),
+            ss => Err(ConfigError::Invalid(format!("No SPI slave select {}", ss))),
+        }
+    }
+
+    fn pins(&self) -> [(&'static str, u8); 3] {
+        [
+            ("dc", self.dc),
+            ("backlight", self.backlight),
+            ("reset", self.reset),
+        ]
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplaysConfig {
+    /// SPI clock of every display, in Hz
+    pub spi_speed: u32,
+    /// 240x240 ST7789
+    pub center: DisplayConfig,
+    /// 160x80 ST7735s
+    pub left: DisplayConfig,
+    /// 160x80 ST7735s
+    pub right: DisplayConfig,
+}
+
+impl Default for DisplaysConfig {
+    fn default() -> Self {
+        Self {
+            spi_speed: 10_000_000,
+            center: DisplayConfig {
+                spi_bus: 1,
+                slave_select: 0,
+                dc: 22,
+                backlight: 19,
+                reset: 27,
+            },
+            left: DisplayConfig {
+                spi_bus: 0,
+                slave_select: 1,
+                dc: 5,
+                backlight: 12,
+                reset: 23,
+            },
+            right: DisplayConfig {
+                spi_bus: 0,
+                slave_select: 0,
+                dc: 4,
+                backlight: 13,
+                reset: 24,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MdnsConfig {
+    pub service_type: String,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self {
+            service_type: "_love-machine._tcp.local.".to_owned(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let source = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        toml::from_str(&source).map_err(|source| ConfigError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    /// Catch what would otherwise only fail once a page is printed or a pin is claimed
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+
+        let printer = &self.printer;
+        if printer.paper_width == 0 {
+            return invalid("printer.paper_width must be positive".to_owned());
+        }
+        if !(1..=printer::MAX_BAND_HEIGHT).contains(&printer.band_height) {
+            return invalid(format!(
+                "printer.band_height must be between 1 and {}",
+                printer::MAX_BAND_HEIGHT
+            ));
+        }
+        if printer.write_timeout == 0 {
+            return invalid("printer.write_timeout must be positive".to_owned());
+        }
+
+        let mut pins = HashSet::new();
+        let buttons = [("key1", self.buttons.key1), ("key2", self.buttons.key2)];
+        let displays = [
+            ("center", &self.displays.center),
+            ("left", &self.displays.left),
+            ("right", &self.displays.right),
+        ];
+        let display_pins = displays.iter().flat_map(|(display, config)| {
+            config
+                .pins()
+                .map(|(pin, number)| (format!("displays.{}.{}", display, pin), number))
+        });
+        for (name, number) in buttons
+            .map(|(button, number)| (format!("buttons.{}", button), number))
+            .into_iter()
+            .chain(display_pins)
+        {
+            if number > MAX_GPIO {
+                return invalid(format!("{}: no GPIO {}", name, number));
+            }
+            if !pins.insert(number) {
+                return invalid(format!("{}: GPIO {} is used twice", name, number));
+            }
+        }
+
+        if self.displays.spi_speed == 0 {
+            return invalid("displays.spi_speed must be positive".to_owned());
+        }
+        let mut chips = HashSet::new();
+        for (name, config) in displays {
+            config.bus()?;
+            config.slave_select()?;
+            if !chips.insert((config.spi_bus, config.slave_select)) {
+                return invalid(format!(
+                    "displays.{}: SPI {}.{} is used twice",
+                    name, config.spi_bus, config.slave_select
+                ));
+            }
+        }
+
+        let service_type = &self.mdns.service_type;
+        if !service_type.starts_with('_')
+            || !(service_type.ends_with("._tcp.local.") || service_type.ends_with("._udp.local."))
+        {
+            return invalid(format!(
+                "mdns.service_type {} is not like _<name>._tcp.local.",
+                service_type
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Config {
+        toml::from_str(source).unwrap()
+    }
+
+    fn rejected(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(message)) => message,
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = Config::default();
+        config.validate().unwrap();
+        assert_eq!(config.heart_path.file_name().unwrap(), "heart.png");
+        assert!(config.heart_path.is_absolute());
+        parse("").validate().unwrap();
+    }
+
+    #[test]
+    fn file_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("love-machine.toml");
+        std::fs::write(
+            &path,
+            r#"
+            listen = "127.0.0.1:8080"
+            [printer]
+            device = "tcp:printer.local:9100"
+            paper_width = 384
+            [buttons]
+            key1 = 5
+            [displays.left]
+            spi_bus = 0
+            slave_select = 1
+            dc = 6
+            backlight = 12
+            reset = 23
+            "#,
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.listen, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert!(matches!(
+            &config.printer.device,
+            Device::Tcp(address) if address == "printer.local:9100"
+        ));
+        assert_eq!(config.printer.paper_width, 384);
+        // Missing keys keep their default
+        assert_eq!(
+            config.printer.band_height,
+            PrinterOptions::default().band_height
+        );
+        assert_eq!((config.buttons.key1, config.buttons.key2), (5, 26));
+        assert_eq!(config.displays.left.dc, 6);
+        config.validate().unwrap();
+
+        std::fs::write(&path, "[printer]\npaper_widht = 384\n").unwrap();
+        assert!(matches!(
+            Config::load(&path),
+            Err(ConfigError::Parse { .. })
+        ));
+        assert!(matches!(
+            Config::load(&dir.path().join("missing.toml")),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        for (source, message) in [
+            ("[printer]\npaper_width = 0", "printer.paper_width"),
+            ("[printer]\nband_height = 0", "printer.band_height"),
+            ("[printer]\nband_height = 4096", "printer.band_height"),
+            ("[printer]\nwrite_timeout = 0", "printer.write_timeout"),
+            ("[displays]\nspi_speed = 0", "displays.spi_speed"),
+            ("[buttons]\nkey1 = 28", "buttons.key1: no GPIO 28"),
+        ] {
+            assert!(rejected(&parse(source)).contains(message), "{}", source);
+        }
+    }
+
+    #[test]
+    fn pins_are_not_shared() {
+        let message = rejected(&parse("[buttons]\nkey1 = 26"));
+        assert_eq!(message, "buttons.key2: GPIO 26 is used twice");
+
+        let mut config = Config::default();
+        config.displays.right.reset = config.displays.center.dc;
+        assert_eq!(
+            rejected(&config),
+            "displays.right.reset: GPIO 22 is used twice"
+        );
+
+        let mut config = Config::default();
+        config.displays.left.slave_select = 0;
+        assert_eq!(rejected(&config), "displays.right: SPI 0.0 is used twice");
+
+        let mut config = Config::default();
+        config.displays.center.spi_bus = 7;
+        assert_eq!(rejected(&config), "No SPI bus 7");
+    }
+
+    #[test]
+    fn service_types() {
+        for service_type in ["_printer._tcp.local.", "_love._udp.local."] {
+            let mut config = Config::default();
+            config.mdns.service_type = service_type.to_owned();
+            config.validate().unwrap();
+        }
+        for service_type in [
+            "",
+            "love-machine._tcp.local.",
+            "_love-machine._tcp.local",
+            "_love-machine._sctp.local.",
+            "_love-machine",
+        ] {
+            let mut config = Config::default();
+            config.mdns.service_type = service_type.to_owned();
+            assert!(rejected(&config).starts_with("mdns.service_type"));
+        }
+    }
+}
@@ -9,20 +9,10 @@ use mipidsi::Builder;
 use rppal::{
     gpio::{Gpio, OutputPin},
     hal::Delay,
-    spi::{Bus, Mode, SlaveSelect, Spi},
+    spi::{Mode, Spi},
 };
 
-const DC0: u8 = 22;
-const BL0: u8 = 19;
-const RST0: u8 = 27;
-
-const DC1: u8 = 4;
-const BL1: u8 = 13;
-const RST1: u8 = 24;
-
-const DC2: u8 = 5;
-const BL2: u8 = 12;
-const RST2: u8 = 23;
+use crate::config::{DisplayConfig, DisplaysConfig};
 
 // Display
 const W0: usize = 240;
@@ -58,23 +48,31 @@ pub struct Displays {
 }
 
 impl Displays {
-    pub fn new() -> Self {
+    pub fn new(config: &DisplaysConfig) -> Self {
         // GPIO
         let gpio = Gpio::new().unwrap();
+        let pins = |display: &DisplayConfig| {
+            (
+                gpio.get(display.dc).unwrap().into_output(),
+                gpio.get(display.backlight).unwrap().into_output(),
+                gpio.get(display.reset).unwrap().into_output(),
+            )
+        };
+        let spi = |display: &DisplayConfig| {
+            Spi::new(
+                display.bus().unwrap(),
+                display.slave_select().unwrap(),
+                config.spi_speed,
+                Mode::Mode0,
+            )
+            .unwrap()
+        };
 
-        let dc0 = gpio.get(DC0).unwrap().into_output();
-        let mut bl_center = gpio.get(BL0).unwrap().into_output();
-        let rst0 = gpio.get(RST0).unwrap().into_output();
-
-        let dc1 = gpio.get(DC1).unwrap().into_output();
-        let mut bl_right = gpio.get(BL1).unwrap().into_output();
-        let rst1 = gpio.get(RST1).unwrap().into_output();
-
-        let dc2 = gpio.get(DC2).unwrap().into_output();
-        let mut bl_left = gpio.get(BL2).unwrap().into_output();
-        let rst2 = gpio.get(RST2).unwrap().into_output();
+        let (dc0, mut bl_center, rst0) = pins(&config.center);
+        let (dc1, mut bl_right, rst1) = pins(&config.right);
+        let (dc2, mut bl_left, rst2) = pins(&config.left);
 
-        let spi0 = Spi::new(Bus::Spi1, SlaveSelect::Ss0, 10000000_u32, Mode::Mode0).unwrap();
+        let spi0 = spi(&config.center);
         let di0 = SPIInterfaceNoCS::new(spi0, dc0);
         let mut delay = Delay::new();
         let d_center = Builder::st7789(di0)
@@ -86,7 +84,7 @@ impl Displays {
             .init(&mut delay, Some(rst0))
             .unwrap();
 
-        let spi1 = Spi::new(Bus::Spi0, SlaveSelect::Ss0, 10000000_u32, Mode::Mode0).unwrap();
+        let spi1 = spi(&config.right);
         let di1 = SPIInterfaceNoCS::new(spi1, dc1);
         let d_right = Builder::st7735s(di1)
             // width and height are switched on purpose because of the orientation
@@ -99,7 +97,7 @@ impl Displays {
             .init(&mut delay, Some(rst1))
             .unwrap();
 
-        let spi2 = Spi::new(Bus::Spi0, SlaveSelect::Ss1, 10000000_u32, Mode::Mode0).unwrap();
+        let spi2 = spi(&config.left);
         let di2 = SPIInterfaceNoCS::new(spi2, dc2);
         let d_left = Builder::st7735s(di2)
             // width and height are switched on purpose because of the orientation
@@ -0,0 +1,408 @@
+//! ESC/POS command encoding, kept free of any I/O
+
+use std::borrow::Cow;
+
+use serde::Serialize;
+
+const ESC: u8 = 0x1B;
+const GS: u8 = 0x1D;
+const DLE: u8 = 0x10;
+const EOT: u8 = 0x04;
+const LF: u8 = 0x0A;
+const FF: u8 = 0x0C;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Justification {
+    Left,
+    Center,
+    Right,
+}
+
+/// Packed 1 bit per dot image, rows padded to a whole byte, MSB is leftmost
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitmap {
+    pub width: u16,
+    pub height: u16,
+    pub data: Vec<u8>,
+}
+
+impl Bitmap {
+    pub fn width_bytes(&self) -> u16 {
+        self.width.div_ceil(8)
+    }
+
+    /// Split into consecutive bitmaps of at most `height` rows
+    pub fn bands(&self, height: u16) -> impl Iterator<Item = Bitmap> + '_ {
+        let band_size = (self.width_bytes() as usize * height as usize).max(1);
+        self.data.chunks(band_size).map(move |chunk| Bitmap {
+            width: self.width,
+            height: (chunk.len() / self.width_bytes() as usize) as u16,
+            data: chunk.to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// ESC @
+    Initialize,
+    /// ESC L
+    SelectPageMode,
+    /// ESC W
+    PrintArea {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    /// ESC $
+    AbsoluteHorizontal(u16),
+    /// GS $, only meaningful in page mode
+    AbsoluteVertical(u16),
+    /// GS !, both magnifications going from 1 to 8
+    CharacterSize { width: u8, height: u8 },
+    /// ESC a
+    Justify(Justification),
+    /// ESC E, emphasized text
+    Bold(bool),
+    /// ESC -, one dot thick underline
+    Underline(bool),
+    /// LF, prints the line buffer and feeds one line
+    LineFeed,
+    /// ESC J
+    FeedDots(u8),
+    /// Plain text, sent as is
+    Text(String),
+    /// GS v 0 in normal density
+    RasterImage(Bitmap),
+    /// FF, prints the page buffer in page mode
+    PrintPage,
+    /// GS V 1, partial cut
+    Cut,
+    /// DLE EOT n
+    StatusRequest(u8),
+}
+
+impl Command {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Command::Initialize => buf.extend_from_slice(&[ESC, b'@']),
+            Command::SelectPageMode => buf.extend_from_slice(&[ESC, b'L']),
+            Command::PrintArea {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                buf.extend_from_slice(&[ESC, b'W']);
+                buf.extend_from_slice(&x.to_le_bytes());
+                buf.extend_from_slice(&y.to_le_bytes());
+                buf.extend_from_slice(&width.to_le_bytes());
+                buf.extend_from_slice(&height.to_le_bytes());
+            }
+            Command::AbsoluteHorizontal(position) => {
+                buf.extend_from_slice(&[ESC, b'$']);
+                buf.extend_from_slice(&position.to_le_bytes());
+            }
+            Command::AbsoluteVertical(position) => {
+                buf.extend_from_slice(&[GS, b'$']);
+                buf.extend_from_slice(&position.to_le_bytes());
+            }
+            Command::CharacterSize { width, height } => {
+                let width = width.clamp(&1, &8) - 1;
+                let height = height.clamp(&1, &8) - 1;
+                buf.extend_from_slice(&[GS, b'!', width << 4 | height]);
+            }
+            Command::Justify(justification) => {
+                let n = match justification {
+                    Justification::Left => 0,
+                    Justification::Center => 1,
+                    Justification::Right => 2,
+                };
+                buf.extend_from_slice(&[ESC, b'a', n]);
+            }
+            Command::Bold(on) => buf.extend_from_slice(&[ESC, b'E', u8::from(*on)]),
+            Command::Underline(on) => buf.extend_from_slice(&[ESC, b'-', u8::from(*on)]),
+            Command::LineFeed => buf.push(LF),
+            Command::FeedDots(dots) => buf.extend_from_slice(&[ESC, b'J', *dots]),
+            Command::Text(text) => buf.extend_from_slice(text.as_bytes()),
+            Command::RasterImage(bitmap) => {
+                buf.extend_from_slice(&[GS, b'v', b'0', 0]);
+                buf.extend_from_slice(&bitmap.width_bytes().to_le_bytes());
+                buf.extend_from_slice(&bitmap.height.to_le_bytes());
+                buf.extend_from_slice(&bitmap.data);
+            }
+            Command::PrintPage => buf.push(FF),
+            Command::Cut => buf.extend_from_slice(&[GS, b'V', b'1']),
+            Command::StatusRequest(n) => buf.extend_from_slice(&[DLE, EOT, *n]),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+}
+
+pub fn encode(commands: &[Command]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for command in commands {
+        command.encode(&mut buf);
+    }
+    buf
+}
+
+/// User supplied text without the control characters that would start commands
+///
+/// Line feeds are kept, anything else below 0x20 and DEL is dropped, so
+/// that e.g. an ESC @ or a DLE DC4 cannot reset or power off the printer.
+pub fn printable(text: &str) -> Cow<'_, str> {
+    let is_command = |c: char| c.is_ascii_control() && c != '\n';
+    if text.contains(is_command) {
+        Cow::Owned(text.chars().filter(|c| !is_command(*c)).collect())
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaperState {
+    Ok,
+    NearEnd,
+    Out,
+}
+
+/// Real-time status, as answered to DLE EOT 1 to 4
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StatusReport {
+    pub online: bool,
+    pub cover_open: bool,
+    pub feed_button: bool,
+    pub paper: PaperState,
+    pub cutter_error: bool,
+    pub unrecoverable_error: bool,
+    pub auto_recoverable_error: bool,
+}
+
+impl StatusReport {
+    /// The requests to send, answers are expected in the same order
+    pub const REQUESTS: [u8; 4] = [1, 2, 3, 4];
+
+    /// Every status byte has bits 1 and 4 set and bits 0 and 7 cleared
+    pub fn is_valid_byte(byte: u8) -> bool {
+        byte & 0x93 == 0x12
+    }
+
+    pub fn decode(printer: u8, offline: u8, error: u8, paper: u8) -> Self {
+        Self {
+            online: printer & 0x08 == 0,
+            cover_open: offline & 0x04 != 0,
+            feed_button: printer & 0x40 != 0 || offline & 0x08 != 0,
+            paper: if paper & 0x60 != 0 {
+                PaperState::Out
+            } else if paper & 0x0C != 0 {
+                PaperState::NearEnd
+            } else {
+                PaperState::Ok
+            },
+            cutter_error: error & 0x08 != 0,
+            unrecoverable_error: error & 0x20 != 0,
+            auto_recoverable_error: error & 0x40 != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(command: Command) -> Vec<u8> {
+        command.to_bytes()
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_eq!(bytes(Command::Initialize), [0x1B, 0x40]);
+        assert_eq!(bytes(Command::SelectPageMode), [0x1B, 0x4C]);
+        assert_eq!(bytes(Command::LineFeed), [0x0A]);
+        assert_eq!(bytes(Command::FeedDots(175)), [0x1B, 0x4A, 175]);
+        assert_eq!(bytes(Command::PrintPage), [0x0C]);
+        assert_eq!(bytes(Command::Cut), [0x1D, 0x56, 0x31]);
+        assert_eq!(bytes(Command::Text("Hé".to_owned())), [b'H', 0xC3, 0xA9]);
+    }
+
+    #[test]
+    fn print_area_is_little_endian() {
+        let command = Command::PrintArea {
+            x: 0x0102,
+            y: 0x0304,
+            width: 576,
+            height: 656,
+        };
+        assert_eq!(
+            bytes(command),
+            [0x1B, 0x57, 0x02, 0x01, 0x04, 0x03, 0x40, 0x02, 0x90, 0x02]
+        );
+    }
+
+    #[test]
+    fn absolute_positions() {
+        assert_eq!(
+            bytes(Command::AbsoluteHorizontal(304)),
+            [0x1B, 0x24, 0x30, 0x01]
+        );
+        assert_eq!(
+            bytes(Command::AbsoluteVertical(0xB8)),
+            [0x1D, 0x24, 0xB8, 0x00]
+        );
+    }
+
+    #[test]
+    fn character_size_packs_nibbles() {
+        let size = |width, height| bytes(Command::CharacterSize { width, height });
+        assert_eq!(size(1, 1), [0x1D, 0x21, 0x00]);
+        assert_eq!(size(4, 4), [0x1D, 0x21, 0x33]);
+        assert_eq!(size(2, 1), [0x1D, 0x21, 0x10]);
+        assert_eq!(size(1, 8), [0x1D, 0x21, 0x07]);
+        // Out of range magnifications are clamped
+        assert_eq!(size(0, 0), [0x1D, 0x21, 0x00]);
+        assert_eq!(size(9, 255), [0x1D, 0x21, 0x77]);
+    }
+
+    #[test]
+    fn text_styles() {
+        assert_eq!(
+            bytes(Command::Justify(Justification::Left)),
+            [0x1B, 0x61, 0]
+        );
+        assert_eq!(
+            bytes(Command::Justify(Justification::Center)),
+            [0x1B, 0x61, 1]
+        );
+        assert_eq!(
+            bytes(Command::Justify(Justification::Right)),
+            [0x1B, 0x61, 2]
+        );
+        assert_eq!(bytes(Command::Bold(true)), [0x1B, 0x45, 1]);
+        assert_eq!(bytes(Command::Bold(false)), [0x1B, 0x45, 0]);
+        assert_eq!(bytes(Command::Underline(true)), [0x1B, 0x2D, 1]);
+        assert_eq!(bytes(Command::Underline(false)), [0x1B, 0x2D, 0]);
+    }
+
+    #[test]
+    fn raster_image_header() {
+        let bitmap = Bitmap {
+            width: 12,
+            height: 2,
+            data: vec![0xFF, 0xF0, 0x80, 0x10],
+        };
+        assert_eq!(
+            bytes(Command::RasterImage(bitmap)),
+            [0x1D, 0x76, 0x30, 0x00, 0x02, 0x00, 0x02, 0x00, 0xFF, 0xF0, 0x80, 0x10]
+        );
+    }
+
+    #[test]
+    fn status_request() {
+        for n in StatusReport::REQUESTS {
+            assert_eq!(bytes(Command::StatusRequest(n)), [0x10, 0x04, n]);
+        }
+    }
+
+    #[test]
+    fn printable_text() {
+        assert!(matches!(
+            printable("Hé ❤ 1/2\n"),
+            Cow::Borrowed("Hé ❤ 1/2\n")
+        ));
+        assert_eq!(printable("a\x1b@b\x10\x14\x01\x02c"), "a@bc");
+        assert_eq!(printable("\x1d!\x77\x0c\r\t\x7f\n"), "!w\n");
+    }
+
+    #[test]
+    fn encode_concatenates() {
+        let commands = [
+            Command::Initialize,
+            Command::Text("A".to_owned()),
+            Command::Cut,
+        ];
+        assert_eq!(encode(&commands), [0x1B, 0x40, b'A', 0x1D, 0x56, 0x31]);
+    }
+
+    #[test]
+    fn bands_split_rows() {
+        let bitmap = Bitmap {
+            width: 9,
+            height: 5,
+            data: (0..10).collect(),
+        };
+        assert_eq!(bitmap.width_bytes(), 2);
+        let bands: Vec<Bitmap> = bitmap.bands(2).collect();
+        assert_eq!(bands.len(), 3);
+        assert_eq!(
+            bands.iter().map(|band| band.height).collect::<Vec<_>>(),
+            [2, 2, 1]
+        );
+        assert!(bands.iter().all(|band| band.width == 9));
+        assert_eq!(bands[1].data, [4, 5, 6, 7]);
+        assert_eq!(bands[2].data, [8, 9]);
+
+        let whole: Vec<Bitmap> = bitmap.bands(256).collect();
+        assert_eq!(whole, std::slice::from_ref(&bitmap));
+    }
+
+    #[test]
+    fn status_bytes_validity() {
+        assert!(StatusReport::is_valid_byte(0x12));
+        assert!(StatusReport::is_valid_byte(0x16));
+        assert!(StatusReport::is_valid_byte(0x7E));
+        assert!(!StatusReport::is_valid_byte(0x00));
+        assert!(!StatusReport::is_valid_byte(0xFF));
+        assert!(!StatusReport::is_valid_byte(0x13));
+        assert!(!StatusReport::is_valid_byte(0x92));
+        assert!(!StatusReport::is_valid_byte(0x10));
+    }
+
+    #[test]
+    fn decode_idle_printer() {
+        let report = StatusReport::decode(0x12, 0x12, 0x12, 0x12);
+        assert_eq!(
+            report,
+            StatusReport {
+                online: true,
+                cover_open: false,
+                feed_button: false,
+                paper: PaperState::Ok,
+                cutter_error: false,
+                unrecoverable_error: false,
+                auto_recoverable_error: false,
+            }
+        );
+    }
+
+    #[test]
+    fn decode_every_flag() {
+        let report = StatusReport::decode(0x12 | 0x08 | 0x40, 0x12 | 0x04, 0x12 | 0x68, 0x12);
+        assert!(!report.online);
+        assert!(report.cover_open);
+        assert!(report.feed_button);
+        assert!(report.cutter_error);
+        assert!(report.unrecoverable_error);
+        assert!(report.auto_recoverable_error);
+
+        // Feed button reported through the offline cause as well
+        assert!(StatusReport::decode(0x12, 0x12 | 0x08, 0x12, 0x12).feed_button);
+    }
+
+    #[test]
+    fn decode_paper_states() {
+        let paper = |byte| StatusReport::decode(0x12, 0x12, 0x12, byte).paper;
+        assert_eq!(paper(0x12), PaperState::Ok);
+        assert_eq!(paper(0x12 | 0x0C), PaperState::NearEnd);
+        assert_eq!(paper(0x12 | 0x60), PaperState::Out);
+        // Out wins over near end
+        assert_eq!(paper(0x12 | 0x6C), PaperState::Out);
+    }
+}
@@ -0,0 +1,37 @@
+//! File helpers shared by the icon catalog and the job spool
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Replace a file through a temporary one, so that readers never see half of it
+///
+/// Temporary files are named `<file>.<n>.tmp`, unique within the process so
+/// that concurrent writers of the same file do not clobber each other. The
+/// data is synced before the rename, and the directory after it.
+pub fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(
+        ".{}.tmp",
+        TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temporary = PathBuf::from(temporary);
+    let result = std::fs::File::create(&temporary)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+        return result;
+    }
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(directory)?.sync_all()
+}
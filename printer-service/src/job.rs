@@ -0,0 +1,162 @@
+use image::{DynamicImage, GenericImageView};
+
+use crate::{
+    escpos::{self, Bitmap, Command, Justification},
+    printer::{PrintError, PrinterOptions},
+    raster::{self, Dithering},
+};
+
+/// A whole ticket, built up front and sent to the printer as a single unit
+///
+/// Nothing reaches the printer before [`crate::printer::Printer::print`],
+/// so concurrent jobs can never interleave their commands.
+#[derive(Debug, Clone)]
+pub struct PrintJob {
+    options: PrinterOptions,
+    commands: Vec<Command>,
+}
+
+impl PrintJob {
+    pub fn new(options: PrinterOptions) -> Self {
+        Self {
+            options,
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        escpos::encode(&self.commands)
+    }
+
+    pub fn command(&mut self, command: Command) -> &mut Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Switch to page mode with a print area starting at the origin
+    pub fn page(&mut self, width: u16, height: u16) -> &mut Self {
+        self.command(Command::SelectPageMode)
+            .command(Command::PrintArea {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            })
+    }
+
+    pub fn font_size(&mut self, size: u8) -> Result<&mut Self, PrintError> {
+        if size > 8 {
+            return Err(PrintError::TooWide);
+        }
+        Ok(self.command(Command::CharacterSize {
+            width: size,
+            height: size,
+        }))
+    }
+
+    /// Page mode position, the vertical one being the text baseline
+    pub fn position(&mut self, horizontal: u16, vertical: u16) -> &mut Self {
+        self.command(Command::AbsoluteHorizontal(horizontal))
+            .command(Command::AbsoluteVertical(vertical))
+    }
+
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        self.command(Command::Text(text.to_owned()))
+    }
+
+    /// Standard mode horizontal line across the whole paper
+    pub fn rule(&mut self, thickness: u16) -> &mut Self {
+        let width = self.options.paper_width;
+        let data = vec![0xFF; usize::from(width.div_ceil(8)) * usize::from(thickness)];
+        self.command(Command::RasterImage(Bitmap {
+            width,
+            height: thickness,
+            data,
+        }))
+    }
+
+    /// Print the page mode buffer and go back to standard mode
+    pub fn print_page(&mut self) -> &mut Self {
+        self.command(Command::PrintPage)
+    }
+
+    fn rasterize(
+        &self,
+        image: &DynamicImage,
+        dithering: Dithering,
+        horizontal: u16,
+    ) -> Result<Bitmap, PrintError> {
+        let (width, height) = image.dimensions();
+        if width + u32::from(horizontal) > u32::from(self.options.paper_width) {
+            return Err(PrintError::TooWide);
+        }
+        if height > u32::from(u16::MAX) {
+            return Err(PrintError::TooTall);
+        }
+        Ok(raster::rasterize(image, dithering))
+    }
+
+    /// Print an image in standard mode, one band after the other
+    pub fn image(
+        &mut self,
+        image: &DynamicImage,
+        dithering: Dithering,
+    ) -> Result<&mut Self, PrintError> {
+        let bitmap = self.rasterize(image, dithering, 0)?;
+        for band in bitmap.bands(self.options.band_height) {
+            self.command(Command::RasterImage(band));
+        }
+        Ok(self)
+    }
+
+    /// Print an image at a page mode position, every band being placed below the previous one
+    pub fn image_at(
+        &mut self,
+        horizontal: u16,
+        vertical: u16,
+        image: &DynamicImage,
+        dithering: Dithering,
+    ) -> Result<&mut Self, PrintError> {
+        let bitmap = self.rasterize(image, dithering, horizontal)?;
+        self.bitmap_at(horizontal, vertical, &bitmap)
+    }
+
+    /// Print an already rasterized image at a page mode position
+    pub fn bitmap_at(
+        &mut self,
+        horizontal: u16,
+        vertical: u16,
+        bitmap: &Bitmap,
+    ) -> Result<&mut Self, PrintError> {
+        if u32::from(bitmap.width) + u32::from(horizontal) > u32::from(self.options.paper_width) {
+            return Err(PrintError::TooWide);
+        }
+        let mut offset = vertical;
+        for band in bitmap.bands(self.options.band_height) {
+            let height = band.height;
+            self.position(horizontal, offset)
+                .command(Command::RasterImage(band));
+            offset = offset.saturating_add(height);
+        }
+        Ok(self)
+    }
+
+    /// Print the footer, feed past the cutter and cut
+    pub fn cut(&mut self) -> &mut Self {
+        self.command(Command::CharacterSize {
+            width: 1,
+            height: 1,
+        })
+        .command(Command::FeedDots(40));
+        if !self.options.footer.is_empty() {
+            let footer = Command::Text(self.options.footer.clone());
+            self.command(Command::Justify(Justification::Center))
+                .command(footer);
+        }
+        self.command(Command::FeedDots(175)).command(Command::Cut)
+    }
+}
@@ -0,0 +1,441 @@
+//! Ticket layouts, stored as `<name>.toml` in a single directory
+//!
+//! A layout is a page mode area holding texts and images, see
+//! `layouts/heart.toml` for the format. The heart layout is built in and
+//! only needs a file to be changed.
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use akri_kubecon_demo::{escpos::Bitmap, job::PrintJob, printer::PrintError, raster::Dithering};
+use axum::http::StatusCode;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::catalog::{self, ICON_SIZE};
+
+/// Used when a request does not pick a layout
+pub const DEFAULT_LAYOUT: &str = "heart";
+const HEART_LAYOUT: &str = include_str!("../layouts/heart.toml");
+
+/// Width of a character at size 1, in dots
+const CHAR_WIDTH: u32 = 12;
+const MAX_TEXT_SIZE: u8 = 8;
+
+#[derive(Debug, Error)]
+pub enum LayoutError {
+    #[error("Invalid layout name")]
+    InvalidName,
+    #[error("Layout not found")]
+    NotFound,
+    #[error("Layout {name} is invalid: {reason}")]
+    Invalid { name: String, reason: String },
+    #[error("Layout storage error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Text does not fit the page: {0}")]
+    TextTooWide(String),
+    #[error("{0}")]
+    Print(#[from] PrintError),
+}
+
+impl From<LayoutError> for (StatusCode, String) {
+    fn from(value: LayoutError) -> Self {
+        let code = match value {
+            LayoutError::InvalidName | LayoutError::TextTooWide(_) => StatusCode::BAD_REQUEST,
+            LayoutError::NotFound => StatusCode::NOT_FOUND,
+            LayoutError::Print(e) => return e.into(),
+            LayoutError::Invalid { .. } | LayoutError::Io(_) => {
+                log::error!("{}", value);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (code, value.to_string())
+    }
+}
+
+/// Which edge or middle of an element its `x` is
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+impl Align {
+    /// Left edge of an element `width` dots wide, none when it starts before the page
+    fn left(self, x: u16, width: u32) -> Option<u32> {
+        let x = u32::from(x);
+        match self {
+            Align::Left => Some(x),
+            Align::Center => x.checked_sub(width / 2),
+            Align::Right => x.checked_sub(width),
+        }
+    }
+}
+
+fn default_text_size() -> u8 {
+    1
+}
+
+fn default_image_size() -> u32 {
+    ICON_SIZE
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Element {
+    /// `{name}` is replaced with the name given with the request
+    Text {
+        text: String,
+        x: u16,
+        y: u16,
+        #[serde(default)]
+        align: Align,
+        /// Character magnification, from 1 to 8
+        #[serde(default = "default_text_size")]
+        size: u8,
+    },
+    /// A fixed image, relative paths starting from the layout directory
+    Image {
+        path: PathBuf,
+        x: u16,
+        y: u16,
+        #[serde(default)]
+        align: Align,
+        /// Bounding box the image is resized into, in dots
+        #[serde(default = "default_image_size")]
+        size: u32,
+        #[serde(default)]
+        dithering: Dithering,
+    },
+    /// The icon picked by the request
+    Icon {
+        x: u16,
+        y: u16,
+        #[serde(default)]
+        align: Align,
+        #[serde(default = "default_image_size")]
+        size: u32,
+    },
+    /// The heart image of the configuration
+    Heart {
+        x: u16,
+        y: u16,
+        #[serde(default)]
+        align: Align,
+        #[serde(default = "default_image_size")]
+        size: u32,
+    },
+}
+
+/// What an image element shows
+pub enum Source<'a> {
+    Icon,
+    Heart,
+    File(&'a Path, Dithering),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    /// Page size, in dots
+    pub width: u16,
+    pub height: u16,
+    #[serde(default)]
+    pub elements: Vec<Element>,
+}
+
+impl Layout {
+    fn validate(&self, paper_width: u16) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err("width and height must be positive".to_owned());
+        }
+        if self.width > paper_width {
+            return Err(format!(
+                "width {} is wider than the paper ({})",
+                self.width, paper_width
+            ));
+        }
+        for (index, element) in self.elements.iter().enumerate() {
+            let fits = match *element {
+                Element::Text { y, size, .. } => {
+                    if !(1..=MAX_TEXT_SIZE).contains(&size) {
+                        return Err(format!(
+                            "element {}: size must be between 1 and {}",
+                            index + 1,
+                            MAX_TEXT_SIZE
+                        ));
+                    }
+                    y < self.height
+                }
+                Element::Image {
+                    x, y, align, size, ..
+                }
+                | Element::Icon { x, y, align, size }
+                | Element::Heart { x, y, align, size } => {
+                    size > 0
+                        && align.left(x, size).is_some_and(|left| {
+                            left + size <= u32::from(self.width)
+                                && u32::from(y) + size <= u32::from(self.height)
+                        })
+                }
+            };
+            if !fits {
+                return Err(format!("element {} is outside the page", index + 1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the page to a job, `bitmap` providing the images
+    pub fn render<E: From<LayoutError>>(
+        &self,
+        job: &mut PrintJob,
+        name: &str,
+        mut bitmap: impl FnMut(Source<'_>, u32) -> Result<Arc<Bitmap>, E>,
+    ) -> Result<(), E> {
+        job.page(self.width, self.height);
+        for element in &self.elements {
+            let (x, y, align, image) = match element {
+                Element::Text {
+                    text,
+                    x,
+                    y,
+                    align,
+                    size,
+                } => {
+                    let text = text.replace("{name}", name);
+                    let width = text.len() as u32 * CHAR_WIDTH * u32::from(*size);
+                    let left = align
+                        .left(*x, width)
+                        .filter(|left| left + width <= u32::from(self.width))
+                        .ok_or(LayoutError::TextTooWide(text.clone()))?;
+                    job.font_size(*size)
+                        .map_err(LayoutError::from)?
+                        .position(left as u16, *y)
+                        .text(&text);
+                    continue;
+                }
+                Element::Image {
+                    path,
+                    x,
+                    y,
+                    align,
+                    size,
+                    dithering,
+                } => (x, y, align, bitmap(Source::File(path, *dithering), *size)?),
+                Element::Icon { x, y, align, size } => (x, y, align, bitmap(Source::Icon, *size)?),
+                Element::Heart { x, y, align, size } => {
+                    (x, y, align, bitmap(Source::Heart, *size)?)
+                }
+            };
+            // Within the page, the bitmap being no larger than the element
+            let left = align.left(*x, u32::from(image.width)).unwrap_or(0);
+            job.bitmap_at(left as u16, *y, &image)
+                .map_err(LayoutError::from)?;
+        }
+        job.print_page();
+        Ok(())
+    }
+}
+
+pub struct Layouts {
+    path: PathBuf,
+    paper_width: u16,
+}
+
+impl Layouts {
+    pub fn new(path: PathBuf, paper_width: u16) -> Self {
+        Self { path, paper_width }
+    }
+
+    /// Where the files of image elements are looked up
+    pub fn image_path(&self, path: &Path) -> PathBuf {
+        self.path.join(path)
+    }
+
+    /// Read and check a layout, its file being read on each use so edits apply right away
+    pub fn get(&self, name: &str) -> Result<Layout, LayoutError> {
+        if !catalog::is_valid_name(name) {
+            return Err(LayoutError::InvalidName);
+        }
+        let source = match std::fs::read_to_string(self.path.join(format!("{}.toml", name))) {
+            Ok(source) => source,
+            Err(e) if e.kind() == ErrorKind::NotFound && name == DEFAULT_LAYOUT => {
+                HEART_LAYOUT.to_owned()
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(LayoutError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        let invalid = |reason: String| LayoutError::Invalid {
+            name: name.to_owned(),
+            reason,
+        };
+        let layout: Layout = toml::from_str(&source).map_err(|e| invalid(e.to_string()))?;
+        layout.validate(self.paper_width).map_err(invalid)?;
+        Ok(layout)
+    }
+
+    /// Layouts that can be used, sorted by name
+    pub fn list(&self) -> Result<Vec<String>, LayoutError> {
+        let mut names = vec![DEFAULT_LAYOUT.to_owned()];
+        let entries = match std::fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(names),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if name == DEFAULT_LAYOUT {
+                continue;
+            }
+            match self.get(name) {
+                Ok(_) => names.push(name.to_owned()),
+                Err(e) => log::warn!("Skipping layout {}: {}", path.display(), e),
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use akri_kubecon_demo::printer::PrinterOptions;
+
+    use super::*;
+
+    fn square(level: u8) -> Arc<Bitmap> {
+        Arc::new(Bitmap {
+            width: ICON_SIZE as u16,
+            height: ICON_SIZE as u16,
+            data: vec![level; (ICON_SIZE / 8 * ICON_SIZE) as usize],
+        })
+    }
+
+    fn render(layout: &Layout, name: &str) -> Result<PrintJob, LayoutError> {
+        let mut job = PrintJob::new(PrinterOptions::default());
+        layout.render::<LayoutError>(&mut job, name, |source, size| {
+            assert_eq!(size, ICON_SIZE);
+            Ok(match source {
+                Source::Icon => square(0xAA),
+                Source::Heart => square(0xFF),
+                Source::File(..) => unreachable!(),
+            })
+        })?;
+        Ok(job)
+    }
+
+    fn parse(source: &str) -> Layout {
+        toml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn heart_layout_matches_the_original_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = Layouts::new(dir.path().to_owned(), 576)
+            .get(DEFAULT_LAYOUT)
+            .unwrap();
+
+        // As the page was drawn before layouts existed
+        let mut original = PrintJob::new(PrinterOptions::default());
+        original
+            .page(576, 656)
+            .font_size(4)
+            .unwrap()
+            .position((576 - 4 * 48) / 2, 0xB8)
+            .text("Akri")
+            .bitmap_at(576 - 256 - 16, 240, &square(0xAA))
+            .unwrap()
+            .bitmap_at(16, 240, &square(0xFF))
+            .unwrap()
+            .print_page();
+        assert_eq!(
+            render(&layout, "Akri").unwrap().commands(),
+            original.commands()
+        );
+    }
+
+    #[test]
+    fn names_wider_than_the_page_are_rejected() {
+        let layout = Layouts::new(PathBuf::new(), 576)
+            .get(DEFAULT_LAYOUT)
+            .unwrap();
+        // 12 characters of 48 dots fill the page
+        render(&layout, &"W".repeat(12)).unwrap();
+        let error = render(&layout, &"W".repeat(13)).unwrap_err();
+        assert!(matches!(error, LayoutError::TextTooWide(_)));
+        let (code, _) = error.into();
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn unknown_layouts_are_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("small.toml"), "width = 288\nheight = 100\n").unwrap();
+        std::fs::write(dir.path().join("broken.toml"), "width = 0\nheight = 1\n").unwrap();
+        let layouts = Layouts::new(dir.path().to_owned(), 576);
+
+        assert_eq!(layouts.get("small").unwrap().width, 288);
+        assert!(matches!(layouts.get("missing"), Err(LayoutError::NotFound)));
+        assert!(matches!(
+            layouts.get("../heart"),
+            Err(LayoutError::InvalidName)
+        ));
+        assert!(matches!(
+            layouts.get("broken"),
+            Err(LayoutError::Invalid { .. })
+        ));
+        assert_eq!(layouts.list().unwrap(), ["heart", "small"]);
+        let (code, _) = LayoutError::NotFound.into();
+        assert_eq!(code, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn elements_outside_the_page_are_invalid() {
+        let page = "width = 576\nheight = 400\n[[elements]]\n";
+        for element in [
+            "type = \"icon\"\nx = 321\ny = 0",
+            "type = \"heart\"\nx = 0\ny = 145",
+            "type = \"icon\"\nx = 100\ny = 0\nalign = \"right\"",
+            "type = \"icon\"\nx = 100\ny = 0\nalign = \"center\"",
+            "type = \"icon\"\nx = 0\ny = 0\nsize = 0",
+            "type = \"image\"\npath = \"logo.png\"\nx = 0\ny = 0\nsize = 577",
+            "type = \"text\"\ntext = \"{name}\"\nx = 0\ny = 400",
+        ] {
+            let layout = parse(&format!("{}{}", page, element));
+            assert_eq!(
+                layout.validate(576).unwrap_err(),
+                "element 1 is outside the page",
+                "{}",
+                element
+            );
+        }
+
+        let layout = parse(&format!(
+            "{}type = \"text\"\ntext = \"{{name}}\"\nx = 0\ny = 0\nsize = 9",
+            page
+        ));
+        assert!(layout.validate(576).is_err());
+        assert!(parse("width = 640\nheight = 400").validate(576).is_err());
+        assert!(parse("width = 576\nheight = 0").validate(576).is_err());
+
+        // Touching the edges is fine
+        let layout = parse(&format!(
+            "{}type = \"icon\"\nx = 576\ny = 144\nalign = \"right\"",
+            page
+        ));
+        layout.validate(576).unwrap();
+    }
+}
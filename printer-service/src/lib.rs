@@ -0,0 +1,7 @@
+pub mod escpos;
+pub mod job;
+pub mod markup;
+pub mod printer;
+pub mod raster;
+pub mod transport;
+pub mod virtual_printer;
@@ -1,75 +1,293 @@
+use akri_kubecon_demo::{
+    escpos::{self, Bitmap, Command, Justification, StatusReport},
+    job::PrintJob,
+    markup,
+    printer::{self, PrintError, Printer, PrinterStatus},
+    raster::{self, Dithering},
+    transport,
+    virtual_printer::VirtualPrinter,
+};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    routing::{get, post},
+    body::Bytes,
+    extract::{
+        multipart::{Multipart, MultipartError},
+        DefaultBodyLimit, Path, Query, State,
+    },
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::sse::{self, KeepAlive, Sse},
+    routing::{get, post, put},
     Json, Router,
 };
-use embedded_graphics::{image::Image, prelude::*};
-use futures::StreamExt;
+use base64::prelude::*;
+use embedded_graphics::{
+    image::Image,
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::Rgb565,
+    prelude::*,
+    text::{Alignment, Text},
+};
+use futures::{Stream, StreamExt};
 use local_ip_address::{local_ip, local_ipv6};
 use mdns_sd::ServiceInfo;
-use printer::{Printer, PrinterStatus};
 use rppal::gpio::Gpio;
-use serde::Deserialize;
-use std::{path::PathBuf, process::ExitCode, sync::Arc, time::Duration};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::{
     select, signal,
     sync::{
-        mpsc::{Receiver, UnboundedReceiver},
+        broadcast,
+        mpsc::{Receiver, Sender, UnboundedReceiver},
         RwLock,
     },
 };
+use tokio_stream::wrappers::BroadcastStream;
 use zbus::Connection;
 
 use clap::Parser;
 use clap_verbosity_flag::Verbosity;
 
-use image::io::Reader as ImageReader;
+use image::{imageops::FilterType, DynamicImage};
 
+use cache::ImageCache;
+use catalog::{CatalogError, IconCatalog, IconSettings, ICON_SIZE};
+use config::{ButtonsConfig, Config, ConfigError};
+use layout::{Layouts, Source};
+use metrics::Metrics;
+use queue::{
+    CancelError, Idempotency, JobKind, JobQueue, JobRecord, JobState, RequestDigest, Submission,
+};
+
+mod cache;
+mod catalog;
+mod config;
 mod displays;
+mod files;
 mod icons;
+mod layout;
+mod metrics;
 mod network;
-mod printer;
-
-fn get_default_icon_path() -> PathBuf {
-    let mut path = std::env::current_exe().unwrap();
-    path.pop();
-    path.push("icons");
-    path
-}
+mod queue;
 
 /// Akri KubeCon EU 2024 Demo
+///
+/// Settings are read from a TOML configuration file, the options below
+/// taking precedence over it.
 #[derive(Debug, Parser)]
 struct Cli {
     #[command(flatten)]
     verbose: Verbosity,
-    #[arg(default_value=get_default_icon_path().into_os_string())]
-    icons_path: PathBuf,
+    /// Configuration file, love-machine.toml next to the executable when it exists
+    #[arg(long, env = "LOVE_MACHINE_CONFIG")]
+    config: Option<PathBuf>,
+    /// Directory holding the icons
+    #[arg(env = "LOVE_MACHINE_ICONS_PATH")]
+    icons_path: Option<PathBuf>,
+    /// Directory holding the ticket layouts
+    #[arg(long, env = "LOVE_MACHINE_LAYOUTS_PATH")]
+    layouts_path: Option<PathBuf>,
+    /// Directory keeping the jobs waiting to be printed
+    #[arg(long, env = "LOVE_MACHINE_SPOOL_PATH")]
+    spool_path: Option<PathBuf>,
+    /// Image printed beside the icon on heart pages
+    #[arg(long, env = "LOVE_MACHINE_HEART_PATH")]
+    heart_path: Option<PathBuf>,
+    /// Printer to drive: lp:<path>, tcp:<host>:<port>, serial:<path>[@<baud>], capture:<path>,
+    /// virtual:<directory> or memory
+    #[arg(long, env = "LOVE_MACHINE_DEVICE")]
+    device: Option<transport::Device>,
+    /// Printable width of the paper in dots
+    #[arg(long, env = "LOVE_MACHINE_PAPER_WIDTH")]
+    paper_width: Option<u16>,
+    /// Maximum rows sent in a single raster command
+    #[arg(long, env = "LOVE_MACHINE_BAND_HEIGHT",
+          value_parser = clap::value_parser!(u16).range(1..=printer::MAX_BAND_HEIGHT as i64))]
+    band_height: Option<u16>,
+    /// How long connecting or writing a chunk of a job may take, in seconds
+    #[arg(long, env = "LOVE_MACHINE_WRITE_TIMEOUT")]
+    write_timeout: Option<u64>,
+    /// Printed at the bottom of every ticket
+    #[arg(long, env = "LOVE_MACHINE_FOOTER")]
+    footer: Option<String>,
+    /// How long an idempotency key keeps returning its original job, in seconds
+    #[arg(long, env = "LOVE_MACHINE_IDEMPOTENCY_WINDOW")]
+    idempotency_window: Option<u64>,
+    /// Address the API listens on
+    #[arg(long, env = "LOVE_MACHINE_LISTEN")]
+    listen: Option<SocketAddr>,
+    /// Service type advertised over mDNS
+    #[arg(long, env = "LOVE_MACHINE_MDNS_SERVICE_TYPE")]
+    mdns_service_type: Option<String>,
 }
 
-#[derive(Debug, PartialEq)]
+impl Cli {
+    /// The configuration file with the options given here applied over it
+    fn config(self) -> Result<Config, ConfigError> {
+        let mut config = match self.config {
+            Some(path) => Config::load(&path)?,
+            None if config::default_path().is_file() => Config::load(&config::default_path())?,
+            None => Config::default(),
+        };
+        if let Some(icons_path) = self.icons_path {
+            config.icons_path = icons_path;
+        }
+        if let Some(layouts_path) = self.layouts_path {
+            config.layouts_path = layouts_path;
+        }
+        if let Some(spool_path) = self.spool_path {
+            config.spool_path = spool_path;
+        }
+        if let Some(heart_path) = self.heart_path {
+            config.heart_path = heart_path;
+        }
+        if let Some(device) = self.device {
+            config.printer.device = device;
+        }
+        if let Some(paper_width) = self.paper_width {
+            config.printer.paper_width = paper_width;
+        }
+        if let Some(band_height) = self.band_height {
+            config.printer.band_height = band_height;
+        }
+        if let Some(write_timeout) = self.write_timeout {
+            config.printer.write_timeout = write_timeout;
+        }
+        if let Some(footer) = self.footer {
+            config.printer.footer = footer;
+        }
+        if let Some(idempotency_window) = self.idempotency_window {
+            config.idempotency_window = idempotency_window;
+        }
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        if let Some(service_type) = self.mdns_service_type {
+            config.mdns.service_type = service_type;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 enum Status {
     Play,
     Pause,
     Discard,
 }
 
+impl Status {
+    const ALL: [Status; 3] = [Status::Play, Status::Pause, Status::Discard];
+
+    /// State reached when pressing a button
+    fn next(self, button: Button) -> Status {
+        match (button, self) {
+            (Button::Key1, Status::Discard) => Status::Pause,
+            (Button::Key1, Status::Pause) => Status::Play,
+            (Button::Key1, Status::Play) => Status::Pause,
+            (Button::Key2, Status::Discard) => Status::Play,
+            (Button::Key2, Status::Pause) => Status::Discard,
+            (Button::Key2, Status::Play) => Status::Discard,
+        }
+    }
+
+    /// The button leading to `target`, none when already there
+    fn button_towards(self, target: Status) -> Option<Button> {
+        [Button::Key1, Button::Key2]
+            .into_iter()
+            .find(|button| self != target && self.next(*button) == target)
+    }
+}
+
+/// State changes streamed on `/events`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    State { state: Status },
+    PrinterStatus { status: PrinterStatus },
+    Network { state: u32 },
+    Button { button: Button },
+    Job(JobRecord),
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::State { .. } => "state",
+            Event::PrinterStatus { .. } => "printer_status",
+            Event::Network { .. } => "network",
+            Event::Button { .. } => "button",
+            Event::Job(_) => "job",
+        }
+    }
+}
+
 struct AppState<'a> {
     printer: Printer,
-    options: Cli,
+    icons: IconCatalog,
+    layouts: Layouts,
+    /// Fixed assets such as the heart
+    images: ImageCache,
+    queue: JobQueue,
+    config: Config,
     status: RwLock<Status>,
     network: network::NetworkManagerProxy<'a>,
+    must_refresh: Sender<()>,
+    metrics: Metrics,
+    events: broadcast::Sender<Event>,
+}
+
+/// Change the machine state the way the buttons do, refused while the printer is not ready
+async fn change_status(
+    state: &AppState<'_>,
+    transition: impl FnOnce(Status) -> Status,
+) -> Result<Status, PrinterStatus> {
+    let printer_status = *state.printer.status.read().await;
+    if printer_status != PrinterStatus::Ok {
+        return Err(printer_status);
+    }
+    let mut status = state.status.write().await;
+    let old_status = *status;
+    *status = transition(old_status);
+    let new_status = *status;
+    drop(status);
+    if new_status != old_status {
+        let _ = state.events.send(Event::State { state: new_status });
+    }
+    state.queue.wake();
+    let _ = state.must_refresh.try_send(());
+    Ok(new_status)
 }
 
 #[derive(Deserialize)]
 struct PrintParams {
     name: String,
+    /// Overrides the icon's dithering mode
+    #[serde(default)]
+    dithering: Option<Dithering>,
+    /// Same as the `Idempotency-Key` header, which takes precedence
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    /// Ticket layout, the heart page when missing
+    #[serde(default)]
+    layout: Option<String>,
 }
 
-const BUTTON_1: u8 = 25;
-const BUTTON_2: u8 = 26;
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Photos are easily larger than the default body limit
+const MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
 enum Button {
     Key1,
     Key2,
@@ -99,77 +317,692 @@ async fn shutdown_signal() {
     }
 }
 
+/// Count rejected submissions, under a bounded set of labels
+fn record_rejection<T>(
+    state: &AppState<'_>,
+    kind: JobKind,
+    icon: &str,
+    result: &Result<T, (StatusCode, String)>,
+) {
+    if let Err((code, _)) = result {
+        if *code != StatusCode::INTERNAL_SERVER_ERROR {
+            state.metrics.job(kind, icon, "rejected");
+        }
+    }
+}
+
+/// The submission's idempotency key, the header taking precedence over `fallback`
+fn idempotency_key(
+    headers: &HeaderMap,
+    fallback: Option<String>,
+) -> Result<Option<String>, (StatusCode, String)> {
+    let invalid = || {
+        (
+            StatusCode::BAD_REQUEST,
+            "Invalid idempotency key".to_owned(),
+        )
+    };
+    let key = match headers.get(IDEMPOTENCY_KEY) {
+        Some(value) => Some(value.to_str().map_err(|_| invalid())?.to_owned()),
+        None => fallback,
+    };
+    match key {
+        Some(key) if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN => Err(invalid()),
+        key => Ok(key),
+    }
+}
+
+/// Hash of a request, `options` telling apart a payload sent with other settings
+fn request_digest(endpoint: &str, options: impl std::fmt::Debug, payload: &[u8]) -> RequestDigest {
+    let options = format!("{:?}", options);
+    let mut hasher = Sha256::new();
+    hasher.update((options.len() as u64).to_le_bytes());
+    hasher.update(options);
+    hasher.update(payload);
+    RequestDigest {
+        endpoint: endpoint.to_owned(),
+        digest: format!("{:x}", hasher.finalize()),
+    }
+}
+
+/// The submission's idempotency key, tied to the endpoint and the request it came with
+fn idempotency(
+    headers: &HeaderMap,
+    fallback: Option<String>,
+    request: impl FnOnce() -> RequestDigest,
+) -> Result<Option<Idempotency>, (StatusCode, String)> {
+    Ok(idempotency_key(headers, fallback)?.map(|key| Idempotency {
+        key,
+        request: request(),
+    }))
+}
+
+/// The answer to a submission whose key was already used
+async fn replay(
+    state: &AppState<'_>,
+    idempotency: Option<&Idempotency>,
+) -> Result<Option<(StatusCode, Json<JobRecord>)>, (StatusCode, String)> {
+    let Some(idempotency) = idempotency else {
+        return Ok(None);
+    };
+    let Some(record) = state.queue.lookup(idempotency).await? else {
+        return Ok(None);
+    };
+    log::info!(
+        "Replaying job {} for idempotency key {}",
+        record.id,
+        idempotency.key
+    );
+    Ok(Some((StatusCode::OK, Json(record))))
+}
+
+/// Spool a job for queue_task, or only record it while discarding
+async fn enqueue(
+    state: &AppState<'_>,
+    kind: JobKind,
+    label: String,
+    idempotency: Option<Idempotency>,
+    job: &PrintJob,
+) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, String)> {
+    if *state.status.read().await == Status::Discard {
+        let Submission { record, replayed } = state.queue.discard(kind, label, idempotency).await?;
+        if !replayed {
+            state.metrics.job(record.kind, record.icon(), "discarded");
+        }
+        return Ok((StatusCode::OK, Json(record)));
+    }
+
+    let submission = state
+        .queue
+        .push(kind, label, idempotency, job.encode())
+        .await?;
+    if submission.replayed {
+        // A concurrent retry with the same key got there first
+        return Ok((StatusCode::OK, Json(submission.record)));
+    }
+    state
+        .metrics
+        .job(submission.record.kind, submission.record.icon(), "queued");
+    // Printed by queue_task once the machine plays and the printer is ready
+    Ok((StatusCode::ACCEPTED, Json(submission.record)))
+}
+
 async fn print_heart_page(
-    State(state): State<Arc<AppState<'_>>>,
+    State(state): State<Arc<AppState<'static>>>,
     Path(icon): Path<String>,
+    headers: HeaderMap,
     Json(payload): Json<PrintParams>,
-) -> Result<(), (StatusCode, String)> {
-    let image = ImageReader::open(state.options.icons_path.join(format!("{}.png", icon)))
-        .map_err(|_| (StatusCode::NOT_FOUND, "Not Found".to_owned()))?
-        .decode()
-        .map_err(|_| (StatusCode::NOT_FOUND, "Not Found".to_owned()))?
-        .resize(256, 256, image::imageops::FilterType::Triangle);
-    let heart = ImageReader::open("heart.png")
-        .unwrap()
-        .decode()
-        .unwrap()
-        .resize(256, 256, image::imageops::FilterType::Triangle);
-
-    let name_size = payload.name.len();
-    if name_size > 12 {
-        return Err((StatusCode::BAD_REQUEST, "Name too big".to_owned()));
-    }
-
-    match *state.status.read().await {
-        Status::Pause => Err((StatusCode::SERVICE_UNAVAILABLE, "Service paused".to_owned())),
-        Status::Discard => Ok(()),
-        Status::Play => {
-            let hpos = (12 - u16::try_from(name_size).unwrap()).saturating_mul(24);
-
-            state.printer.set_page(576, 656).await?;
-            state.printer.set_font_size(4).await?;
-            state.printer.set_position(hpos, 0xB8).await?;
-            state.printer.write(&payload.name).await?;
-            state.printer.set_position(576 - 256 - 16, 240).await?;
-            state.printer.print_image(&image).await?;
-            state.printer.set_position(16, 240).await?;
-            state.printer.print_image(&heart).await?;
-            state.printer.print_page().await?;
-            state.printer.cut().await?;
-            Ok(())
-        }
-    }
-}
-
-async fn list_icons(State(state): State<Arc<AppState<'_>>>) -> axum::response::Json<Vec<String>> {
-    let path_iter = std::fs::read_dir(&state.options.icons_path).unwrap();
-    path_iter
-        .filter_map(|e| {
-            if let Ok(entry) = e {
-                return Some(
-                    std::path::Path::new(&entry.file_name())
-                        .file_stem()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_string(),
-                );
+) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, String)> {
+    let result = submit_heart_page(&state, icon.clone(), headers, payload).await;
+    // Unknown icons must not create a label each
+    let label = if state.icons.contains(&icon) {
+        icon.as_str()
+    } else {
+        ""
+    };
+    record_rejection(&state, JobKind::Heart, label, &result);
+    result
+}
+
+/// Run decoding, rasterizing or PNG encoding on the blocking thread pool, off the workers
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, (StatusCode, String)> + Send + 'static,
+) -> Result<T, (StatusCode, String)> {
+    tokio::task::spawn_blocking(work).await.map_err(|e| {
+        log::error!("Image processing failed: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Image processing failed".to_owned(),
+        )
+    })?
+}
+
+/// A fixed image of a layout, a missing one being a server side issue
+fn load_image(
+    state: &AppState<'_>,
+    path: &std::path::Path,
+    size: u32,
+    dithering: Dithering,
+) -> Result<Arc<Bitmap>, (StatusCode, String)> {
+    state.images.get(path, size, dithering).map_err(|e| {
+        log::error!("Unable to load {}: {}", path.display(), e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Image {} unavailable", path.display()),
+        )
+    })
+}
+
+async fn submit_heart_page(
+    state: &Arc<AppState<'static>>,
+    icon: String,
+    headers: HeaderMap,
+    payload: PrintParams,
+) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, String)> {
+    let idempotency = idempotency(&headers, payload.idempotency_key.clone(), || {
+        let options = (&icon, payload.dithering, &payload.layout);
+        request_digest("love", options, payload.name.as_bytes())
+    })?;
+    if let Some(replayed) = replay(state, idempotency.as_ref()).await? {
+        return Ok(replayed);
+    }
+
+    let job = {
+        let (state, icon) = (state.clone(), icon.clone());
+        blocking(move || heart_page(&state, &icon, &payload)).await?
+    };
+    enqueue(state, JobKind::Heart, icon, idempotency, &job).await
+}
+
+/// The job printing a name and an icon with a layout
+fn heart_page(
+    state: &AppState<'_>,
+    icon: &str,
+    payload: &PrintParams,
+) -> Result<PrintJob, (StatusCode, String)> {
+    // Even when the layout does not show it, the icon labels the job
+    if !state.icons.contains(icon) {
+        return Err(CatalogError::NotFound.into());
+    }
+    let dithering = payload
+        .dithering
+        .unwrap_or_else(|| state.icons.settings(icon).dithering);
+    let layout = state
+        .layouts
+        .get(payload.layout.as_deref().unwrap_or(layout::DEFAULT_LAYOUT))?;
+
+    let mut job = state.printer.job();
+    layout.render(&mut job, &payload.name, |source, size| match source {
+        Source::Icon => Ok(state.icons.bitmap(icon, size, dithering)?),
+        Source::Heart => load_image(state, &state.config.heart_path, size, Dithering::Threshold),
+        Source::File(path, dithering) => {
+            load_image(state, &state.layouts.image_path(path), size, dithering)
+        }
+    })?;
+    job.cut();
+    Ok(job)
+}
+
+/// PNG body along with its content type
+type Png = ([(HeaderName, &'static str); 1], Vec<u8>);
+
+/// What a job looks like on paper, as rendered by the virtual printer
+fn preview(state: &AppState<'_>, job: &PrintJob) -> Result<Png, (StatusCode, String)> {
+    let paper_width = u32::from(state.config.printer.paper_width);
+    let ticket = VirtualPrinter::render(paper_width, &job.encode())
+        .into_iter()
+        .next()
+        .ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Nothing to preview".to_owned(),
+        ))?;
+    let png = catalog::encode_png(&DynamicImage::ImageLuma8(ticket))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(([(header::CONTENT_TYPE, "image/png")], png))
+}
+
+/// The heart page `POST /love/:icon` would print, as a PNG
+async fn preview_heart_page(
+    State(state): State<Arc<AppState<'static>>>,
+    Path(icon): Path<String>,
+    Json(payload): Json<PrintParams>,
+) -> Result<Png, (StatusCode, String)> {
+    blocking(move || preview(&state, &heart_page(&state, &icon, &payload)?)).await
+}
+
+#[derive(Deserialize)]
+struct LayoutPreviewParams {
+    name: String,
+    icon: String,
+    #[serde(default)]
+    dithering: Option<Dithering>,
+}
+
+/// A layout filled with a name and an icon, as a PNG
+async fn preview_layout(
+    State(state): State<Arc<AppState<'static>>>,
+    Path(layout): Path<String>,
+    Json(params): Json<LayoutPreviewParams>,
+) -> Result<Png, (StatusCode, String)> {
+    let payload = PrintParams {
+        name: params.name,
+        dithering: params.dithering,
+        idempotency_key: None,
+        layout: Some(layout),
+    };
+    blocking(move || preview(&state, &heart_page(&state, &params.icon, &payload)?)).await
+}
+
+/// How an uploaded image is scaled to the paper
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Fit {
+    /// Shrink images wider than the paper, keep the others as they are
+    #[default]
+    Fit,
+    /// Scale to the whole paper width
+    Fill,
+}
+
+/// Room left below a caption, in dots
+const CAPTION_FEED: u8 = 24;
+
+fn parse_field<T>(
+    name: &str,
+    value: &str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<T, (StatusCode, String)> {
+    parse(value).ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid {}: {}", name, value),
+        )
+    })
+}
+
+/// Print any image, sent as the `image` field of a multipart form
+///
+/// The other fields are `fit` (fit or fill), `dithering`, `align` (left,
+/// center or right), `caption` and `cut` (true or false, defaults to true).
+async fn print_image(
+    State(state): State<Arc<AppState<'_>>>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, String)> {
+    let result = submit_image(&state, headers, multipart).await;
+    record_rejection(&state, JobKind::Image, "", &result);
+    result
+}
+
+async fn submit_image(
+    state: &AppState<'_>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, String)> {
+    let bad_request = |e: MultipartError| (StatusCode::BAD_REQUEST, e.body_text());
+    let mut data = None;
+    let mut fit = Fit::default();
+    let mut dithering = Dithering::FloydSteinberg;
+    let mut align = Justification::Center;
+    let mut caption = None;
+    let mut cut = true;
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        let name = field.name().unwrap_or_default().to_owned();
+        if name == "image" {
+            data = Some(field.bytes().await.map_err(bad_request)?);
+            continue;
+        }
+        let value = field.text().await.map_err(bad_request)?;
+        match name.as_str() {
+            "fit" => {
+                fit = parse_field(&name, &value, |value| match value {
+                    "fit" => Some(Fit::Fit),
+                    "fill" => Some(Fit::Fill),
+                    _ => None,
+                })?
+            }
+            "dithering" => dithering = parse_field(&name, &value, |value| value.parse().ok())?,
+            "align" => {
+                align = parse_field(&name, &value, |value| match value {
+                    "left" => Some(Justification::Left),
+                    "center" => Some(Justification::Center),
+                    "right" => Some(Justification::Right),
+                    _ => None,
+                })?
             }
-            None
+            // Control characters would reach the printer as commands
+            "caption" => {
+                caption = Some(escpos::printable(&value).into_owned())
+                    .filter(|caption| !caption.is_empty())
+            }
+            "cut" => cut = parse_field(&name, &value, |value| value.parse().ok())?,
+            _ => return Err((StatusCode::BAD_REQUEST, format!("Unknown field: {}", name))),
+        }
+    }
+
+    let data = data.ok_or((StatusCode::BAD_REQUEST, "Missing image".to_owned()))?;
+    let idempotency = idempotency(&headers, None, || {
+        let options = (fit, dithering, align, &caption, cut);
+        request_digest("image", options, &data)
+    })?;
+    if let Some(replayed) = replay(state, idempotency.as_ref()).await? {
+        return Ok(replayed);
+    }
+    let paper_width = u32::from(state.config.printer.paper_width);
+    let mut job = state.printer.job();
+    let mut job = blocking(move || {
+        let image = image::load_from_memory(&data)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid image: {}", e)))?;
+        let image = match fit {
+            Fit::Fit if image.width() <= paper_width => image,
+            _ => image.resize(paper_width, u32::MAX, FilterType::Triangle),
+        };
+        job.command(Command::Justify(align))
+            .image(&image, dithering)?;
+        Ok(job)
+    })
+    .await?;
+    if let Some(caption) = &caption {
+        job.font_size(1)?
+            .text(caption)
+            .command(Command::FeedDots(CAPTION_FEED));
+    }
+    if cut {
+        job.cut();
+    }
+    enqueue(state, JobKind::Image, "image".to_owned(), idempotency, &job).await
+}
+
+#[derive(Deserialize)]
+struct TextParams {
+    /// Cut after the text, defaults to true
+    #[serde(default)]
+    cut: Option<bool>,
+}
+
+/// Print a text ticket written in the markup of [`markup`]
+async fn print_text(
+    State(state): State<Arc<AppState<'_>>>,
+    Query(params): Query<TextParams>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, String)> {
+    let result = submit_text(&state, params, headers, body).await;
+    record_rejection(&state, JobKind::Text, "", &result);
+    result
+}
+
+async fn submit_text(
+    state: &AppState<'_>,
+    params: TextParams,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(StatusCode, Json<JobRecord>), (StatusCode, String)> {
+    let idempotency = idempotency(&headers, None, || {
+        request_digest("text", params.cut, body.as_bytes())
+    })?;
+    if let Some(replayed) = replay(state, idempotency.as_ref()).await? {
+        return Ok(replayed);
+    }
+
+    let mut job = state.printer.job();
+    markup::render(&mut job, &body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if params.cut.unwrap_or(true) {
+        job.cut();
+    }
+    enqueue(state, JobKind::Text, "text".to_owned(), idempotency, &job).await
+}
+
+async fn list_jobs(State(state): State<Arc<AppState<'_>>>) -> Json<Vec<JobRecord>> {
+    Json(state.queue.list().await)
+}
+
+async fn get_job(
+    State(state): State<Arc<AppState<'_>>>,
+    Path(id): Path<u64>,
+) -> Result<Json<JobRecord>, (StatusCode, String)> {
+    state
+        .queue
+        .get(id)
+        .await
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Not Found".to_owned()))
+}
+
+async fn cancel_job(
+    State(state): State<Arc<AppState<'_>>>,
+    Path(id): Path<u64>,
+) -> Result<Json<JobRecord>, (StatusCode, String)> {
+    match state.queue.cancel(id).await {
+        Ok(record) => {
+            state.metrics.job(record.kind, record.icon(), "cancelled");
+            Ok(Json(record))
+        }
+        Err(CancelError::NotFound) => Err((StatusCode::NOT_FOUND, "Not Found".to_owned())),
+        Err(e @ CancelError::NotQueued(_)) => Err((StatusCode::CONFLICT, e.to_string())),
+    }
+}
+
+async fn list_layouts(
+    State(state): State<Arc<AppState<'_>>>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    Ok(Json(state.layouts.list()?))
+}
+
+async fn list_icons(
+    State(state): State<Arc<AppState<'_>>>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    Ok(Json(state.icons.list()?))
+}
+
+#[derive(Deserialize)]
+struct UploadParams {
+    /// Stored as the icon's dithering mode
+    #[serde(default)]
+    dithering: Option<Dithering>,
+}
+
+#[derive(Serialize)]
+struct IconInfo {
+    name: String,
+    dithering: Dithering,
+    /// Stored icon, base64 encoded PNG
+    original: String,
+    /// The icon as it prints on the heart page, base64 encoded PNG
+    preview: String,
+}
+
+fn icon_info(icons: &IconCatalog, name: &str) -> Result<IconInfo, (StatusCode, String)> {
+    let data = icons.read(name)?;
+    let dithering = icons.settings(name).dithering;
+    let bitmap = icons.bitmap(name, ICON_SIZE, dithering)?;
+    let preview = catalog::encode_png(&DynamicImage::ImageLuma8(raster::preview(&bitmap)))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(IconInfo {
+        name: name.to_owned(),
+        dithering,
+        original: BASE64_STANDARD.encode(&data),
+        preview: BASE64_STANDARD.encode(preview),
+    })
+}
+
+async fn get_icon(
+    State(state): State<Arc<AppState<'static>>>,
+    Path(name): Path<String>,
+) -> Result<Json<IconInfo>, (StatusCode, String)> {
+    blocking(move || icon_info(&state.icons, &name))
+        .await
+        .map(Json)
+}
+
+/// Store an uploaded image as an icon, trimmed and no wider than the paper
+async fn upload_icon(
+    State(state): State<Arc<AppState<'static>>>,
+    Path(name): Path<String>,
+    Query(params): Query<UploadParams>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<IconInfo>), (StatusCode, String)> {
+    if !catalog::is_valid_name(&name) {
+        return Err(CatalogError::InvalidName.into());
+    }
+    let (created, info) = blocking(move || {
+        let image = image::load_from_memory(&body).map_err(CatalogError::InvalidImage)?;
+        let mut image = raster::trim(&image);
+        let paper_width = u32::from(state.config.printer.paper_width);
+        if image.width() > paper_width {
+            image = image.resize(paper_width, u32::MAX, FilterType::Triangle);
+        }
+
+        let settings = params.dithering.map(|dithering| IconSettings { dithering });
+        let created = state.icons.store(&name, &image, settings.as_ref())?;
+        log::info!(
+            "Stored icon {} ({}x{})",
+            name,
+            image.width(),
+            image.height()
+        );
+        Ok((created, icon_info(&state.icons, &name)?))
+    })
+    .await?;
+
+    let code = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((code, Json(info)))
+}
+
+async fn delete_icon(
+    State(state): State<Arc<AppState<'_>>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state.icons.remove(&name)?;
+    log::info!("Removed icon {}", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct PrinterInfo {
+    status: PrinterStatus,
+    report: Option<StatusReport>,
+}
+
+async fn printer_status(State(state): State<Arc<AppState<'_>>>) -> Json<PrinterInfo> {
+    Json(PrinterInfo {
+        status: *state.printer.status.read().await,
+        report: *state.printer.report.read().await,
+    })
+}
+
+/// Liveness: answering at all is enough
+async fn healthz() -> &'static str {
+    "OK"
+}
+
+#[derive(Serialize)]
+struct FailedCheck {
+    check: &'static str,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    failed: Vec<FailedCheck>,
+}
+
+/// Readiness: whether a submitted ticket would be printed right away
+async fn readyz(State(state): State<Arc<AppState<'_>>>) -> (StatusCode, Json<Readiness>) {
+    let mut failed = Vec::new();
+    let printer_status = *state.printer.status.read().await;
+    if printer_status != PrinterStatus::Ok {
+        failed.push(FailedCheck {
+            check: "printer",
+            reason: format!("Printer status is {:?}", printer_status),
+        });
+    }
+    let status = *state.status.read().await;
+    if status != Status::Play {
+        failed.push(FailedCheck {
+            check: "state",
+            reason: format!("Machine is in {:?} state", status),
+        });
+    }
+    match state.network.state().await {
+        Ok(nmstate) if nmstate >= network::CONNECTED_LOCAL => {}
+        Ok(nmstate) => failed.push(FailedCheck {
+            check: "network",
+            reason: format!("NetworkManager state is {}", nmstate),
+        }),
+        Err(e) => failed.push(FailedCheck {
+            check: "network",
+            reason: format!("Unable to get NetworkManager state: {}", e),
+        }),
+    }
+    let ready = failed.is_empty();
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(Readiness { ready, failed }))
+}
+
+async fn render_metrics(
+    State(state): State<Arc<AppState<'_>>>,
+) -> Result<String, (StatusCode, String)> {
+    let status = *state.status.read().await;
+    let printer_status = *state.printer.status.read().await;
+    let network_state = state.network.state().await.ok();
+    state
+        .metrics
+        .render(
+            status,
+            &Status::ALL,
+            printer_status,
+            &state.printer.stats,
+            network_state,
+        )
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateInfo {
+    state: Status,
+}
+
+/// Server-sent events for every state change, job and button press
+async fn events(
+    State(state): State<Arc<AppState<'_>>>,
+) -> Sse<impl Stream<Item = Result<sse::Event, axum::Error>>> {
+    let events = BroadcastStream::new(state.events.subscribe());
+    let jobs = BroadcastStream::new(state.queue.subscribe()).map(|job| job.map(Event::Job));
+    let stream = futures::stream::select(events, jobs)
+        .filter_map(|event| async move {
+            // Slow clients miss what they lagged behind on
+            event.ok()
         })
-        .collect::<Vec<_>>()
-        .into()
+        .map(|event| sse::Event::default().event(event.name()).json_data(event))
+        // Streams never end by themselves, which would hold the graceful shutdown
+        .take_until(shutdown_signal());
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn get_state(State(state): State<Arc<AppState<'_>>>) -> Json<StateInfo> {
+    Json(StateInfo {
+        state: *state.status.read().await,
+    })
+}
+
+async fn put_state(
+    State(state): State<Arc<AppState<'_>>>,
+    Json(payload): Json<StateInfo>,
+) -> Result<Json<StateInfo>, (StatusCode, String)> {
+    let target = payload.state;
+    let new_status = change_status(&state, |status| match status.button_towards(target) {
+        Some(button) => status.next(button),
+        None => status,
+    })
+    .await
+    .map_err(|printer_status| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Printer is not ready: {:?}", printer_status),
+        )
+    })?;
+    log::info!("State set to {:?} through the API", new_status);
+    Ok(Json(StateInfo { state: new_status }))
 }
 
-fn setup_buttons() -> UnboundedReceiver<Button> {
+fn setup_buttons(pins: &ButtonsConfig) -> UnboundedReceiver<Button> {
     let (s, r) = tokio::sync::mpsc::unbounded_channel();
 
     // GPIO
     let gpio = Gpio::new().unwrap();
 
     // Buttons
-    let mut button_a = gpio.get(BUTTON_1).unwrap().into_input_pullup();
-    let mut button_b = gpio.get(BUTTON_2).unwrap().into_input_pullup();
+    let mut button_a = gpio.get(pins.key1).unwrap().into_input_pullup();
+    let mut button_b = gpio.get(pins.key2).unwrap().into_input_pullup();
 
     let button_sender = s.clone();
 
@@ -190,8 +1023,23 @@ fn setup_buttons() -> UnboundedReceiver<Button> {
     r
 }
 
+/// Short enough to fit under the printer icon
+fn status_label(status: PrinterStatus) -> &'static str {
+    match status {
+        PrinterStatus::Ok => "",
+        PrinterStatus::PaperNearEnd => "PAPER LOW",
+        PrinterStatus::NoPaper => "NO PAPER",
+        PrinterStatus::CoverOpen => "COVER OPEN",
+        PrinterStatus::CutterError => "CUTTER",
+        PrinterStatus::UnrecoverableError => "FAILURE",
+        PrinterStatus::AutoRecoverableError => "RECOVERING",
+        PrinterStatus::Offline => "OFFLINE",
+        PrinterStatus::PrinterNotConnected => "UNPLUGGED",
+    }
+}
+
 async fn display_task(state: Arc<AppState<'_>>, mut must_refresh: Receiver<()>) {
-    let mut disp = displays::Displays::new();
+    let mut disp = displays::Displays::new(&state.config.displays);
 
     let play_small = icons::get_play_small();
     let pause_small = icons::get_pause_small();
@@ -251,17 +1099,27 @@ async fn display_task(state: Arc<AppState<'_>>, mut must_refresh: Receiver<()>)
                     .draw(&mut disp.left.color_converted())
                     .unwrap();
             }
-            _ => {
+            status => {
                 Image::new(&print_nok_small, Point { x: 0, y: 80 })
                     .draw(&mut disp.left.color_converted())
                     .unwrap();
+                Text::with_alignment(
+                    status_label(status),
+                    Point { x: 40, y: 156 },
+                    MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE),
+                    Alignment::Center,
+                )
+                .draw(&mut disp.left)
+                .unwrap();
             }
         }
 
         match state.network.state().await {
-            Ok(nmstate) if nmstate >= 50 => Image::new(&wireless_ok_small, Point::zero())
-                .draw(&mut disp.left.color_converted())
-                .unwrap(),
+            Ok(nmstate) if nmstate >= network::CONNECTED_LOCAL => {
+                Image::new(&wireless_ok_small, Point::zero())
+                    .draw(&mut disp.left.color_converted())
+                    .unwrap()
+            }
             _ => Image::new(&wireless_nok_small, Point::zero())
                 .draw(&mut disp.left.color_converted())
                 .unwrap(),
@@ -276,6 +1134,88 @@ async fn display_task(state: Arc<AppState<'_>>, mut must_refresh: Receiver<()>)
     }
 }
 
+/// Sending attempts of a job failing while the printer is there and idle
+const MAX_PRINT_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled after each failed attempt
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+async fn fail_job(state: &AppState<'_>, job: &JobRecord, reason: String) {
+    state
+        .queue
+        .finish(job.id, JobState::Failed { reason })
+        .await;
+    state.metrics.job(job.kind, job.icon(), "failed");
+}
+
+async fn queue_task(state: Arc<AppState<'_>>) {
+    loop {
+        let ready = *state.status.read().await == Status::Play
+            && *state.printer.status.read().await == PrinterStatus::Ok;
+        if ready {
+            match state.queue.start().await {
+                Some((job, Ok(data))) => {
+                    let started = Instant::now();
+                    match state.printer.send(data).await {
+                        Ok(_) => {
+                            log::info!("Printed job {} ({})", job.id, job.label);
+                            state.queue.finish(job.id, JobState::Printed).await;
+                            let latency = SystemTime::now()
+                                .duration_since(UNIX_EPOCH + Duration::from_secs(job.submitted_at))
+                                .unwrap_or_default();
+                            state.metrics.printed(
+                                job.kind,
+                                job.icon(),
+                                started.elapsed().as_secs_f64(),
+                                latency.as_secs_f64(),
+                            );
+                            continue;
+                        }
+                        // Part of the ticket may be on paper, sending it again would print it twice
+                        Err(e @ (PrintError::Timeout | PrintError::Incomplete)) => {
+                            log::error!("Job {} failed while printing: {}", job.id, e);
+                            fail_job(&state, &job, e.to_string()).await;
+                            continue;
+                        }
+                        // Nothing of the job was sent to an away or busy printer, it waits as long as needed
+                        Err(e)
+                            if !matches!(e, PrintError::NotConnected | PrintError::Busy)
+                                && job.attempts + 1 >= MAX_PRINT_ATTEMPTS =>
+                        {
+                            log::error!("Giving up on job {}: {}", job.id, e);
+                            let reason = format!("{} after {} attempts", e, MAX_PRINT_ATTEMPTS);
+                            fail_job(&state, &job, reason).await;
+                            continue;
+                        }
+                        Err(e) => {
+                            log::warn!("Unable to print job {}, will retry: {}", job.id, e);
+                            state.queue.requeue(job.id).await;
+                            let delay = RETRY_DELAY
+                                .saturating_mul(1 << job.attempts.min(16))
+                                .min(MAX_RETRY_DELAY);
+                            select! {
+                                _ = tokio::time::sleep(delay) => continue,
+                                _ = shutdown_signal() => break,
+                            };
+                        }
+                    }
+                }
+                Some((job, Err(e))) => {
+                    log::error!("Dropping job {} with unreadable payload: {}", job.id, e);
+                    fail_job(&state, &job, format!("Unreadable payload: {}", e)).await;
+                    continue;
+                }
+                None => {}
+            }
+        }
+        select! {
+            _ = state.queue.woken() => {},
+            _ = tokio::time::sleep(Duration::from_secs(2)) => {},
+            _ = shutdown_signal() => break,
+        };
+    }
+}
+
 #[tokio::main]
 async fn main() -> ExitCode {
     let cli = Cli::parse();
@@ -284,7 +1224,54 @@ async fn main() -> ExitCode {
         .filter_level(cli.verbose.log_level_filter())
         .init();
 
-    let printer = printer::Printer::new(std::path::Path::new("/dev/usb/lp0")).await;
+    let config = match cli.config() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let printer =
+        printer::Printer::new(config.printer.device.clone(), config.printer.options()).await;
+
+    let queue = match JobQueue::open(
+        config.spool_path.clone(),
+        Duration::from_secs(config.idempotency_window),
+    )
+    .await
+    {
+        Ok(queue) => queue,
+        Err(e) => {
+            log::error!(
+                "Unable to open spool {}: {}",
+                config.spool_path.display(),
+                e
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let images = ImageCache::new();
+    // Loaded once here so that a missing heart shows up before any print
+    if let Err(e) = images.get(&config.heart_path, ICON_SIZE, Dithering::Threshold) {
+        log::error!("Unable to load {}: {}", config.heart_path.display(), e);
+        return ExitCode::FAILURE;
+    }
+
+    let layouts = Layouts::new(config.layouts_path.clone(), config.printer.paper_width);
+    if let Err(e) = layouts.get(layout::DEFAULT_LAYOUT) {
+        log::error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let metrics = match Metrics::new() {
+        Ok(metrics) => metrics,
+        Err(e) => {
+            log::error!("Unable to set up metrics: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
     let connection = Connection::system().await.unwrap();
 
@@ -292,11 +1279,20 @@ async fn main() -> ExitCode {
         .await
         .unwrap();
 
+    let (must_refresh, refresh_rec) = tokio::sync::mpsc::channel(1);
+
     let state = Arc::new(AppState {
         printer,
-        options: cli,
+        icons: IconCatalog::new(config.icons_path.clone()),
+        layouts,
+        images,
+        queue,
+        config,
         status: RwLock::new(Status::Pause),
         network: proxy,
+        must_refresh,
+        metrics,
+        events: broadcast::channel(64).0,
     });
 
     let mut tasks = vec![];
@@ -305,10 +1301,36 @@ async fn main() -> ExitCode {
     let app = Router::new()
         .route("/love", get(list_icons))
         .route("/love/:icon", post(print_heart_page))
+        .route("/love/:icon/preview", post(preview_heart_page))
+        .route("/layouts", get(list_layouts))
+        .route("/layouts/:layout/preview", post(preview_layout))
+        .route(
+            "/print/image",
+            post(print_image).layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE)),
+        )
+        .route("/print/text", post(print_text))
+        .route(
+            "/icons/:name",
+            get(get_icon).post(upload_icon).delete(delete_icon),
+        )
+        .route("/printer", get(printer_status))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/:id", get(get_job).delete(cancel_job))
+        .route("/state", put(put_state).get(get_state))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(render_metrics))
+        .route("/events", get(events))
         .with_state(state.clone());
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    // run our app with hyper
+    let listener = match tokio::net::TcpListener::bind(state.config.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Unable to listen on {}: {}", state.config.listen, e);
+            return ExitCode::FAILURE;
+        }
+    };
     tasks.push(tokio::spawn(async {
         axum::serve(listener, app)
             .with_graceful_shutdown(shutdown_signal())
@@ -316,14 +1338,13 @@ async fn main() -> ExitCode {
             .unwrap()
     }));
 
-    let (must_refresh, refresh_rec) = tokio::sync::mpsc::channel(1);
-
     tasks.push(tokio::spawn(display_task(state.clone(), refresh_rec)));
 
+    tasks.push(tokio::spawn(queue_task(state.clone())));
+
     let local_state = state.clone();
-    let local_must_refresh = must_refresh.clone();
     tasks.push(tokio::spawn(async move {
-        let mut r = setup_buttons();
+        let mut r = setup_buttons(&local_state.config.buttons);
         let state = local_state.clone();
         loop {
             let button = select! {
@@ -336,51 +1357,51 @@ async fn main() -> ExitCode {
                 }
             };
             log::debug!("Got button push: {:?}", button);
-            if *state.printer.status.read().await != PrinterStatus::Ok {
+            let _ = state.events.send(Event::Button { button });
+            if change_status(&state, |status| status.next(button))
+                .await
+                .is_err()
+            {
                 log::debug!("Doing nothing as printer is not ready");
-                continue;
-            }
-            match button {
-                Button::Key1 => {
-                    let mut status = state.status.write().await;
-                    *status = match *status {
-                        Status::Discard => Status::Pause,
-                        Status::Pause => Status::Play,
-                        Status::Play => Status::Pause,
-                    }
-                }
-                Button::Key2 => {
-                    let mut status = state.status.write().await;
-                    *status = match *status {
-                        Status::Discard => Status::Play,
-                        Status::Pause => Status::Discard,
-                        Status::Play => Status::Discard,
-                    }
-                }
             }
-            let _ = local_must_refresh.try_send(());
         }
     }));
 
-    let local_must_refresh = must_refresh.clone();
     let local_state = state.clone();
     tasks.push(tokio::spawn(async move {
         let mut nmstate_stream = local_state.network.receive_state_changed().await;
         loop {
             select! {
-                Some(_) = nmstate_stream.next() => {let _ = local_must_refresh.try_send(());},
+                Some(change) = nmstate_stream.next() => {
+                    if let Ok(nmstate) = change.get().await {
+                        let _ = local_state.events.send(Event::Network { state: nmstate });
+                    }
+                    let _ = local_state.must_refresh.try_send(());
+                },
                 _ = shutdown_signal() => break,
             }
         }
     }));
 
+    let publish_mdns = publish_mdns(
+        state.config.mdns.service_type.clone(),
+        state.config.listen.port(),
+    );
+
     tasks.push(tokio::spawn(async move {
         loop {
             let new_status = state.printer.get_status().await;
             let mut old_status = state.printer.status.write().await;
             if *old_status != new_status {
-                log::info!("Printer status changed: {:?}", new_status);
+                log::info!(
+                    "Printer status changed: {:?} ({:?})",
+                    new_status,
+                    *state.printer.report.read().await
+                );
                 *old_status = new_status;
+                let _ = state
+                    .events
+                    .send(Event::PrinterStatus { status: new_status });
                 match *old_status {
                     PrinterStatus::Ok => {
                         let _ = state.printer.cut().await;
@@ -388,11 +1409,15 @@ async fn main() -> ExitCode {
                     _ => {
                         let mut status = state.status.write().await;
                         if *status == Status::Play {
-                            *status = Status::Pause
+                            *status = Status::Pause;
+                            let _ = state.events.send(Event::State {
+                                state: Status::Pause,
+                            });
                         }
                     }
                 }
-                let _ = must_refresh.try_send(());
+                state.queue.wake();
+                let _ = state.must_refresh.try_send(());
             }
             select! {
                 _ = shutdown_signal() => break,
@@ -401,14 +1426,14 @@ async fn main() -> ExitCode {
         }
     }));
 
-    tasks.push(tokio::spawn(publish_mdns()));
+    tasks.push(tokio::spawn(publish_mdns));
 
     futures::future::join_all(tasks).await;
 
     ExitCode::SUCCESS
 }
 
-async fn publish_mdns() {
+async fn publish_mdns(service_type: String, port: u16) {
     let daemon = mdns_sd::ServiceDaemon::new().expect("Unable to start mdns daemon");
     let mut ips = vec![];
     if let Ok(v4) = local_ip() {
@@ -419,14 +1444,78 @@ async fn publish_mdns() {
     }
     let hostname = gethostname::gethostname().into_string().expect("Invalid Hostname");
     let service = ServiceInfo::new(
-        "_love-machine._tcp.local.",
+        &service_type,
         &hostname,
         &hostname,
         ips.as_slice(),
-        3000,
+        port,
         None,
     ).unwrap();
     daemon.register(service).unwrap();
     shutdown_signal().await;
     daemon.shutdown().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_override_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("love-machine.toml");
+        std::fs::write(
+            &path,
+            r#"
+            spool_path = "/var/spool/love-machine"
+            [printer]
+            paper_width = 384
+            write_timeout = 30
+            footer = "KubeCon"
+            [mdns]
+            service_type = "_printer._tcp.local."
+            "#,
+        )
+        .unwrap();
+        // Only read by this test
+        std::env::set_var("LOVE_MACHINE_FOOTER", "Akri");
+        std::env::set_var("LOVE_MACHINE_WRITE_TIMEOUT", "20");
+
+        let config = Cli::try_parse_from([
+            "love-machine",
+            "--config",
+            path.to_str().unwrap(),
+            "--paper-width",
+            "576",
+            "--write-timeout",
+            "5",
+            "--mdns-service-type",
+            "_love._tcp.local.",
+            "/srv/icons",
+        ])
+        .unwrap()
+        .config()
+        .unwrap();
+        assert_eq!(config.icons_path, PathBuf::from("/srv/icons"));
+        assert_eq!(config.spool_path, PathBuf::from("/var/spool/love-machine"));
+        assert_eq!(config.printer.paper_width, 576);
+        // The command line wins over the environment, itself winning over the file
+        assert_eq!(config.printer.write_timeout, 5);
+        assert_eq!(config.printer.footer, "Akri");
+        assert_eq!(config.mdns.service_type, "_love._tcp.local.");
+
+        // Checked once merged
+        let result = Cli::try_parse_from([
+            "love-machine",
+            "--config",
+            path.to_str().unwrap(),
+            "--paper-width",
+            "0",
+        ])
+        .unwrap()
+        .config();
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+        std::env::remove_var("LOVE_MACHINE_FOOTER");
+        std::env::remove_var("LOVE_MACHINE_WRITE_TIMEOUT");
+    }
+}
@@ -0,0 +1,371 @@
+//! A small line based markup for text tickets
+//!
+//! ```text
+//! # Heading            centered, bold, double width and height
+//! ## Subheading        bold, double height
+//! ---                  horizontal rule
+//! {center,big} Text    line settings: left, center, right, wide, tall, big
+//! **bold** __under__   inline emphasis and underline, reset at end of line
+//! ```
+//!
+//! Every line is printed on its own, an empty line just feeds the paper.
+//! Control characters are dropped, the text cannot carry raw commands.
+
+use thiserror::Error;
+
+use crate::{
+    escpos::{self, Command, Justification},
+    job::PrintJob,
+};
+
+/// Thickness of a horizontal rule, in dots
+const RULE_THICKNESS: u16 = 2;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MarkupError {
+    #[error("Line {line}: unknown setting {setting}")]
+    UnknownSetting { line: usize, setting: String },
+    #[error("Line {line}: unterminated settings")]
+    UnterminatedSettings { line: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LineStyle {
+    justification: Justification,
+    width: u8,
+    height: u8,
+    bold: bool,
+}
+
+impl Default for LineStyle {
+    fn default() -> Self {
+        Self {
+            justification: Justification::Left,
+            width: 1,
+            height: 1,
+            bold: false,
+        }
+    }
+}
+
+/// Split the optional `{...}` settings from the text of a line
+fn parse_settings(number: usize, line: &str) -> Result<(LineStyle, &str), MarkupError> {
+    let mut style = LineStyle::default();
+    let Some(rest) = line.strip_prefix('{') else {
+        return Ok((style, line));
+    };
+    let (settings, text) = rest
+        .split_once('}')
+        .ok_or(MarkupError::UnterminatedSettings { line: number })?;
+    for setting in settings.split(',').map(str::trim) {
+        match setting {
+            "left" => style.justification = Justification::Left,
+            "center" => style.justification = Justification::Center,
+            "right" => style.justification = Justification::Right,
+            "wide" => style.width = 2,
+            "tall" => style.height = 2,
+            "big" => (style.width, style.height) = (2, 2),
+            "" => {}
+            _ => {
+                return Err(MarkupError::UnknownSetting {
+                    line: number,
+                    setting: setting.to_owned(),
+                })
+            }
+        }
+    }
+    Ok((style, text.strip_prefix(' ').unwrap_or(text)))
+}
+
+/// Print user text, dropping control characters so that it cannot carry commands
+fn push_text(job: &mut PrintJob, text: &str) {
+    let text = escpos::printable(text);
+    if !text.is_empty() {
+        job.text(&text);
+    }
+}
+
+/// Emit a line of text, turning `**` and `__` into emphasis and underline toggles
+fn inline(job: &mut PrintJob, text: &str, bold: bool) {
+    let (mut emphasized, mut underlined) = (false, false);
+    let mut rest = text;
+    while let Some(index) = rest.find(['*', '_']) {
+        let marker = &rest[index..];
+        if marker.starts_with("**") {
+            push_text(job, &rest[..index]);
+            emphasized = !emphasized;
+            job.command(Command::Bold(bold || emphasized));
+        } else if marker.starts_with("__") {
+            push_text(job, &rest[..index]);
+            underlined = !underlined;
+            job.command(Command::Underline(underlined));
+        } else {
+            push_text(job, &rest[..=index]);
+            rest = &rest[index + 1..];
+            continue;
+        }
+        rest = &rest[index + 2..];
+    }
+    push_text(job, rest);
+    if emphasized {
+        job.command(Command::Bold(bold));
+    }
+    if underlined {
+        job.command(Command::Underline(false));
+    }
+}
+
+/// Append the commands printing a markup document to a job
+pub fn render(job: &mut PrintJob, source: &str) -> Result<(), MarkupError> {
+    // Whatever the previous job left, start from the defaults
+    let mut current = LineStyle::default();
+    job.command(Command::Justify(current.justification))
+        .command(Command::CharacterSize {
+            width: current.width,
+            height: current.height,
+        })
+        .command(Command::Bold(current.bold));
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim_end();
+        if line.trim() == "---" {
+            job.rule(RULE_THICKNESS);
+            continue;
+        }
+        let (style, text) = if let Some(text) = line.strip_prefix("## ") {
+            let style = LineStyle {
+                height: 2,
+                bold: true,
+                ..Default::default()
+            };
+            (style, text)
+        } else if let Some(text) = line.strip_prefix("# ") {
+            let style = LineStyle {
+                justification: Justification::Center,
+                width: 2,
+                height: 2,
+                bold: true,
+            };
+            (style, text)
+        } else {
+            parse_settings(index + 1, line)?
+        };
+
+        if style.justification != current.justification {
+            job.command(Command::Justify(style.justification));
+        }
+        if (style.width, style.height) != (current.width, current.height) {
+            job.command(Command::CharacterSize {
+                width: style.width,
+                height: style.height,
+            });
+        }
+        if style.bold != current.bold {
+            job.command(Command::Bold(style.bold));
+        }
+        current = style;
+        inline(job, text, style.bold);
+        job.command(Command::LineFeed);
+    }
+    // Leave the printer as the other jobs expect it
+    if current != LineStyle::default() {
+        job.command(Command::Justify(Justification::Left))
+            .command(Command::CharacterSize {
+                width: 1,
+                height: 1,
+            })
+            .command(Command::Bold(false));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer::PrinterOptions;
+
+    /// Initial defaults of every document
+    const PROLOGUE: [Command; 3] = [
+        Command::Justify(Justification::Left),
+        Command::CharacterSize {
+            width: 1,
+            height: 1,
+        },
+        Command::Bold(false),
+    ];
+
+    fn commands(source: &str) -> Result<Vec<Command>, MarkupError> {
+        let mut job = PrintJob::new(PrinterOptions::default());
+        render(&mut job, source)?;
+        let commands = job.commands();
+        assert_eq!(commands[..3], PROLOGUE);
+        Ok(commands[3..].to_vec())
+    }
+
+    fn text(text: &str) -> Command {
+        Command::Text(text.to_owned())
+    }
+
+    #[test]
+    fn plain_lines() {
+        assert_eq!(
+            commands("Hello\n\nWorld").unwrap(),
+            [
+                text("Hello"),
+                Command::LineFeed,
+                Command::LineFeed,
+                text("World"),
+                Command::LineFeed,
+            ]
+        );
+    }
+
+    #[test]
+    fn headings() {
+        let mut expected = vec![
+            Command::Justify(Justification::Center),
+            Command::CharacterSize {
+                width: 2,
+                height: 2,
+            },
+            Command::Bold(true),
+            text("Title"),
+            Command::LineFeed,
+            Command::Justify(Justification::Left),
+            Command::CharacterSize {
+                width: 1,
+                height: 2,
+            },
+            text("Subtitle"),
+            Command::LineFeed,
+        ];
+        expected.extend(PROLOGUE);
+        assert_eq!(commands("# Title\n## Subtitle").unwrap(), expected);
+        // Without the space they are plain text
+        assert_eq!(commands("#Tag").unwrap(), [text("#Tag"), Command::LineFeed]);
+    }
+
+    #[test]
+    fn settings_lines() {
+        let mut expected = vec![
+            Command::Justify(Justification::Right),
+            Command::CharacterSize {
+                width: 2,
+                height: 2,
+            },
+            text("Big"),
+            Command::LineFeed,
+            Command::Justify(Justification::Center),
+            Command::CharacterSize {
+                width: 2,
+                height: 1,
+            },
+            text("{Wide}"),
+            Command::LineFeed,
+        ];
+        expected.extend(PROLOGUE);
+        assert_eq!(
+            commands("{right, big} Big\n{wide,center,}{Wide}").unwrap(),
+            expected
+        );
+        assert_eq!(
+            commands("{} Plain").unwrap(),
+            [text("Plain"), Command::LineFeed]
+        );
+    }
+
+    #[test]
+    fn rules() {
+        let commands = commands("  ---  ").unwrap();
+        assert_eq!(commands.len(), 1);
+        assert!(matches!(&commands[0], Command::RasterImage(bitmap)
+            if bitmap.width == 576 && bitmap.height == RULE_THICKNESS));
+    }
+
+    #[test]
+    fn inline_toggles() {
+        assert_eq!(
+            commands("a **b** __c__ d").unwrap(),
+            [
+                text("a "),
+                Command::Bold(true),
+                text("b"),
+                Command::Bold(false),
+                text(" "),
+                Command::Underline(true),
+                text("c"),
+                Command::Underline(false),
+                text(" d"),
+                Command::LineFeed,
+            ]
+        );
+    }
+
+    #[test]
+    fn emphasis_in_a_bold_line_stays_bold() {
+        let commands = commands("## a **b** c").unwrap();
+        assert_eq!(
+            commands[2..8],
+            [
+                text("a "),
+                Command::Bold(true),
+                text("b"),
+                Command::Bold(true),
+                text(" c"),
+                Command::LineFeed,
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_markers_end_with_the_line() {
+        assert_eq!(
+            commands("**bold __both\nplain").unwrap(),
+            [
+                Command::Bold(true),
+                text("bold "),
+                Command::Underline(true),
+                text("both"),
+                Command::Bold(false),
+                Command::Underline(false),
+                Command::LineFeed,
+                text("plain"),
+                Command::LineFeed,
+            ]
+        );
+    }
+
+    #[test]
+    fn single_markers_are_text() {
+        assert_eq!(
+            commands("2*3 a_b").unwrap(),
+            [text("2*"), text("3 a_"), text("b"), Command::LineFeed]
+        );
+    }
+
+    #[test]
+    fn control_characters_are_dropped() {
+        assert_eq!(
+            commands("a\x1b@b\x10\x14\x01\x02c\x1d\x1b").unwrap(),
+            [text("a@bc"), Command::LineFeed]
+        );
+        assert_eq!(
+            commands("\x1b**\x1b").unwrap(),
+            [Command::Bold(true), Command::Bold(false), Command::LineFeed]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            commands("ok\n{center"),
+            Err(MarkupError::UnterminatedSettings { line: 2 })
+        );
+        assert_eq!(
+            commands("ok\n\n{center,blink} x"),
+            Err(MarkupError::UnknownSetting {
+                line: 3,
+                setting: "blink".to_owned(),
+            })
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Prometheus metrics, served on `/metrics`
+
+use std::sync::{atomic::Ordering, Mutex};
+
+use akri_kubecon_demo::printer::{PrinterStats, PrinterStatus};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use serde::Serialize;
+
+use crate::queue::JobKind;
+
+pub struct Metrics {
+    registry: Registry,
+    /// Jobs by kind, icon and outcome: queued, printed, discarded, cancelled, failed or
+    /// rejected. The icon is empty for text and image jobs, and for unknown icons.
+    jobs: IntCounterVec,
+    /// Time spent sending a job to the printer
+    send_duration: Histogram,
+    /// Time from submission to the job being printed
+    job_latency: Histogram,
+    machine_state: IntGaugeVec,
+    printer_status: IntGaugeVec,
+    connections: IntCounter,
+    bytes_written: IntCounter,
+    network_state: IntGauge,
+    /// Held from refreshing the gauges until they are gathered, scrapes being concurrent
+    rendering: Mutex<()>,
+}
+
+/// Snake case name of a state, as found in the API
+fn label<T: Serialize>(value: T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// A series per known value, the current one set to 1 and the others to 0
+fn set_current<T: Serialize + Copy + PartialEq>(gauge: &IntGaugeVec, current: T, all: &[T]) {
+    for value in all {
+        gauge
+            .with_label_values(&[&label(*value)])
+            .set(i64::from(*value == current));
+    }
+}
+
+/// Bring a counter up to a total tracked elsewhere
+fn catch_up(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("love_machine".to_owned()), None)?;
+        let jobs = IntCounterVec::new(
+            Opts::new("jobs_total", "Print jobs by kind, icon and outcome"),
+            &["kind", "icon", "outcome"],
+        )?;
+        let send_duration = Histogram::with_opts(HistogramOpts::new(
+            "print_send_duration_seconds",
+            "Time spent sending a job to the printer",
+        ))?;
+        let job_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "print_latency_seconds",
+                "Time from submission to the job being printed",
+            )
+            .buckets(vec![1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0]),
+        )?;
+        let machine_state = IntGaugeVec::new(
+            Opts::new("state", "Current Play, Pause or Discard state"),
+            &["state"],
+        )?;
+        let printer_status = IntGaugeVec::new(
+            Opts::new("printer_status", "Current printer status"),
+            &["status"],
+        )?;
+        let connections = IntCounter::new(
+            "printer_connections_total",
+            "Connections opened to the printer, reconnections included",
+        )?;
+        let bytes_written = IntCounter::new(
+            "printer_written_bytes_total",
+            "Bytes written to the printer",
+        )?;
+        let network_state = IntGauge::new("network_state", "NetworkManager state")?;
+
+        registry.register(Box::new(jobs.clone()))?;
+        registry.register(Box::new(send_duration.clone()))?;
+        registry.register(Box::new(job_latency.clone()))?;
+        registry.register(Box::new(machine_state.clone()))?;
+        registry.register(Box::new(printer_status.clone()))?;
+        registry.register(Box::new(connections.clone()))?;
+        registry.register(Box::new(bytes_written.clone()))?;
+        registry.register(Box::new(network_state.clone()))?;
+
+        Ok(Self {
+            registry,
+            jobs,
+            send_duration,
+            job_latency,
+            machine_state,
+            printer_status,
+            connections,
+            bytes_written,
+            network_state,
+            rendering: Mutex::new(()),
+        })
+    }
+
+    pub fn job(&self, kind: JobKind, icon: &str, outcome: &str) {
+        self.jobs
+            .with_label_values(&[&label(kind), icon, outcome])
+            .inc();
+    }
+
+    pub fn printed(&self, kind: JobKind, icon: &str, send_duration: f64, latency: f64) {
+        self.job(kind, icon, "printed");
+        self.send_duration.observe(send_duration);
+        self.job_latency.observe(latency);
+    }
+
+    /// Refresh the gauges and render everything in the text format
+    pub fn render<S: Serialize + Copy + PartialEq>(
+        &self,
+        state: S,
+        states: &[S],
+        printer_status: PrinterStatus,
+        stats: &PrinterStats,
+        network_state: Option<u32>,
+    ) -> prometheus::Result<String> {
+        let _rendering = self.rendering.lock().unwrap_or_else(|e| e.into_inner());
+        set_current(&self.machine_state, state, states);
+        set_current(&self.printer_status, printer_status, &PrinterStatus::ALL);
+        catch_up(&self.connections, stats.connections.load(Ordering::Relaxed));
+        catch_up(
+            &self.bytes_written,
+            stats.bytes_written.load(Ordering::Relaxed),
+        );
+        // NM_STATE_UNKNOWN when NetworkManager cannot be reached
+        self.network_state.set(network_state.map_or(0, i64::from));
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
@@ -1,5 +1,8 @@
 use zbus::proxy;
 
+/// `NM_STATE_CONNECTED_LOCAL`, the lowest state with a usable network
+pub const CONNECTED_LOCAL: u32 = 50;
+
 #[proxy(
     interface = "org.freedesktop.NetworkManager",
     default_service = "org.freedesktop.NetworkManager",
@@ -1,15 +1,23 @@
 use std::{
-    fs::File,
-    io::{ErrorKind, Read, Write},
-    path::{Path, PathBuf},
-    time::Duration,
+    io::{ErrorKind, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use axum::http::StatusCode;
-use image::{DynamicImage, GenericImageView, Pixel};
+use serde::Serialize;
 
 use thiserror::Error;
-use tokio::sync::{Mutex, MutexGuard, RwLock};
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+
+use crate::{
+    escpos::{Command, PaperState, StatusReport},
+    job::PrintJob,
+    transport::{Device, Transport},
+};
 
 #[derive(Debug, Error, PartialEq)]
 pub enum PrintError {
@@ -21,247 +29,578 @@ pub enum PrintError {
     NotConnected,
     #[error("Print paused")]
     Paused,
+    #[error("Printer did not answer in time")]
+    Timeout,
+    #[error("Printer stopped in the middle of the job")]
+    Incomplete,
+    #[error("Printer is busy with another job")]
+    Busy,
 }
 
 impl From<PrintError> for (StatusCode, String) {
     fn from(value: PrintError) -> Self {
-        (StatusCode::SERVICE_UNAVAILABLE, value.to_string())
+        let code = match value {
+            // Retrying cannot help, the job itself is at fault
+            PrintError::TooWide => StatusCode::BAD_REQUEST,
+            PrintError::TooTall => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (code, value.to_string())
     }
 }
 
+/// Highest band a single GS v 0 command can carry
+pub const MAX_BAND_HEIGHT: u16 = 4095;
+
+/// Jobs are written in chunks of this many bytes, each one getting the whole write timeout
+const WRITE_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct PrinterOptions {
+    /// Printable width in dots
+    pub paper_width: u16,
+    /// Images are sent in slices of at most this many rows
+    pub band_height: u16,
+    /// How long the printer gets to answer a status poll
+    pub status_timeout: Duration,
+    /// How long connecting or writing a single chunk of a job may take
+    pub write_timeout: Duration,
+    /// Printed above every cut, nothing when empty
+    pub footer: String,
+}
+
+impl Default for PrinterOptions {
+    fn default() -> Self {
+        Self {
+            paper_width: 576,
+            band_height: 256,
+            status_timeout: Duration::from_secs(2),
+            write_timeout: Duration::from_secs(10),
+            footer: "Akri Demo for KubeCon EU 2024".to_owned(),
+        }
+    }
+}
+
+/// Running totals since startup
+#[derive(Debug, Default)]
+pub struct PrinterStats {
+    /// Connections opened to the device, the first one included
+    pub connections: AtomicU64,
+    /// Bytes successfully written to the device, status requests included
+    pub bytes_written: AtomicU64,
+}
+
+impl PrinterStats {
+    fn written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+type Guard = OwnedMutexGuard<Option<Box<dyn Transport>>>;
+
+/// Shared between blocking I/O and the printer waiting for it
+#[derive(Default)]
+struct Progress {
+    steps: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    fn advance(&self) {
+        self.steps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Fails once the printer gave up waiting, so that nothing more gets written
+    fn check(&self) -> std::io::Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "given up after a stall",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Keep the device locked until abandoned blocking I/O is really over
+///
+/// Otherwise the next job would open a second handle to the device and the
+/// bytes of both would interleave. `abandoned` stays set meanwhile, as an lp
+/// write may never return.
+fn release_when_done<T: Send + 'static>(
+    guard: Guard,
+    task: tokio::task::JoinHandle<T>,
+    abandoned: Arc<AtomicBool>,
+) {
+    abandoned.store(true, Ordering::Relaxed);
+    tokio::spawn(async move {
+        let _ = task.await;
+        log::debug!("Abandoned printer I/O is over");
+        abandoned.store(false, Ordering::Relaxed);
+        drop(guard);
+    });
+}
+
 pub struct Printer {
-    fd: Mutex<Option<File>>,
-    path: PathBuf,
+    fd: Arc<Mutex<Option<Box<dyn Transport>>>>,
+    /// Set while given up I/O still holds the device
+    abandoned: Arc<AtomicBool>,
+    device: Device,
+    options: PrinterOptions,
     pub status: RwLock<PrinterStatus>,
+    /// Detailed status from the last successful poll
+    pub report: RwLock<Option<StatusReport>>,
+    pub stats: PrinterStats,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PrinterStatus {
     Ok,
     PaperNearEnd,
     NoPaper,
+    CoverOpen,
+    CutterError,
+    UnrecoverableError,
+    AutoRecoverableError,
+    Offline,
     PrinterNotConnected,
 }
 
+impl PrinterStatus {
+    pub const ALL: [PrinterStatus; 9] = [
+        PrinterStatus::Ok,
+        PrinterStatus::PaperNearEnd,
+        PrinterStatus::NoPaper,
+        PrinterStatus::CoverOpen,
+        PrinterStatus::CutterError,
+        PrinterStatus::UnrecoverableError,
+        PrinterStatus::AutoRecoverableError,
+        PrinterStatus::Offline,
+        PrinterStatus::PrinterNotConnected,
+    ];
+}
+
+impl From<&StatusReport> for PrinterStatus {
+    /// Most severe condition wins
+    fn from(report: &StatusReport) -> Self {
+        if report.unrecoverable_error {
+            PrinterStatus::UnrecoverableError
+        } else if report.cutter_error {
+            PrinterStatus::CutterError
+        } else if report.auto_recoverable_error {
+            PrinterStatus::AutoRecoverableError
+        } else if report.cover_open {
+            PrinterStatus::CoverOpen
+        } else if report.paper == PaperState::Out {
+            PrinterStatus::NoPaper
+        } else if !report.online {
+            PrinterStatus::Offline
+        } else if report.paper == PaperState::NearEnd {
+            PrinterStatus::PaperNearEnd
+        } else {
+            PrinterStatus::Ok
+        }
+    }
+}
+
 impl Printer {
-    pub async fn new(path: &Path) -> Self {
+    pub async fn new(device: Device, options: PrinterOptions) -> Self {
         let printer = Printer {
-            fd: Mutex::new(None),
-            path: path.to_path_buf(),
+            fd: Arc::new(Mutex::new(None)),
+            abandoned: Arc::new(AtomicBool::new(false)),
+            device,
+            options: PrinterOptions {
+                band_height: options.band_height.clamp(1, MAX_BAND_HEIGHT),
+                ..options
+            },
             status: RwLock::new(PrinterStatus::PrinterNotConnected),
+            report: RwLock::new(None),
+            stats: PrinterStats::default(),
         };
         let _ = printer.connect().await;
-        {
-            let mut status = printer.status.write().await;
-            *status = printer.get_status().await;
-        }
+        let status = printer.get_status().await;
+        *printer.status.write().await = status;
         printer
     }
 
     pub async fn connect(&self) -> Result<(), PrintError> {
-        let mut fd = self.fd.lock().await;
-        if fd.is_some() {
-            return Ok(());
-        }
-        match File::options().write(true).read(true).open(&self.path) {
-            Ok(mut file) => {
-                // Initialize printer
-                file.write_all(&[0x1b, 0x40])
-                    .map_err(|_| PrintError::NotConnected)?;
-                *fd = Some(file);
+        self.get_guard(self.options.write_timeout).await.map(drop)
+    }
+
+    /// Open the device, `guard` being the empty slot of the transport
+    async fn open(&self, mut guard: Guard) -> Result<Guard, PrintError> {
+        let device = self.device.clone();
+        let initialize = Command::Initialize.to_bytes();
+        let initialize_len = initialize.len();
+        let mut task =
+            tokio::task::spawn_blocking(move || -> std::io::Result<Box<dyn Transport>> {
+                let mut transport = device.open()?;
+                transport.write_all(&initialize)?;
+                Ok(transport)
+            });
+        match tokio::time::timeout(self.options.write_timeout, &mut task).await {
+            Ok(Ok(Ok(transport))) => {
+                *guard = Some(transport);
+                self.stats.connections.fetch_add(1, Ordering::Relaxed);
+                self.stats.written(initialize_len);
+                Ok(guard)
+            }
+            Ok(_) => Err(PrintError::NotConnected),
+            // Nothing of a job was sent yet
+            Err(_) => {
+                release_when_done(guard, task, self.abandoned.clone());
+                Err(PrintError::NotConnected)
             }
-            Err(_) => return Err(PrintError::NotConnected),
         }
-        Ok(())
     }
 
-    async fn get_guard(&self) -> Result<MutexGuard<'_, Option<File>>, PrintError> {
-        let guard = self.fd.lock().await;
-        match *guard {
-            Some(_) => Ok(guard),
-            None => {
-                drop(guard);
-                if self.connect().await.is_ok() {
-                    let guard = self.fd.lock().await;
-                    match *guard {
-                        Some(_) => Ok(guard),
-                        None => Err(PrintError::NotConnected),
+    /// Run blocking transport I/O on the blocking thread pool
+    ///
+    /// The transport is handed back to the guard only when `io` succeeds,
+    /// so any failure leaves the printer disconnected. `io` is given up on
+    /// once it makes no [`Progress`] for `timeout`, the device staying
+    /// locked until it actually returns. Failing before any progress is
+    /// [`PrintError::NotConnected`], nothing having reached the device.
+    async fn run_blocking<T, F>(
+        &self,
+        mut guard: Guard,
+        timeout: Duration,
+        io: F,
+    ) -> Result<T, PrintError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn Transport, &Progress) -> std::io::Result<T> + Send + 'static,
+    {
+        let mut transport = guard.take().ok_or(PrintError::NotConnected)?;
+        let progress = Arc::new(Progress::default());
+        let task_progress = progress.clone();
+        let mut task = tokio::task::spawn_blocking(move || {
+            let result = io(transport.as_mut(), &task_progress);
+            (transport, result)
+        });
+        let mut steps = 0;
+        loop {
+            match tokio::time::timeout(timeout, &mut task).await {
+                Ok(Ok((transport, Ok(value)))) => {
+                    *guard = Some(transport);
+                    return Ok(value);
+                }
+                Ok(Ok((_, Err(e)))) => {
+                    log::warn!("Printer I/O failed: {}", e);
+                    if progress.steps.load(Ordering::Relaxed) > 0 {
+                        return Err(PrintError::Incomplete);
                     }
-                } else {
-                    Err(PrintError::NotConnected)
+                    return Err(PrintError::NotConnected);
+                }
+                Ok(Err(e)) => {
+                    log::error!("Printer I/O task failed: {}", e);
+                    return Err(PrintError::NotConnected);
+                }
+                Err(_) => {
+                    let current = progress.steps.load(Ordering::Relaxed);
+                    if current == steps {
+                        break;
+                    }
+                    steps = current;
                 }
             }
         }
+        log::warn!("Printer I/O stalled for {:?}", timeout);
+        progress.cancelled.store(true, Ordering::Relaxed);
+        release_when_done(guard, task, self.abandoned.clone());
+        if steps == 0 {
+            return Err(PrintError::NotConnected);
+        }
+        Err(PrintError::Timeout)
     }
 
-    pub async fn cut(&self) -> Result<(), PrintError> {
-        self.set_font_size(1).await?;
-        let mut guard = self.get_guard().await?;
-        let mut fd = guard.as_ref().unwrap();
-        (|| -> std::io::Result<()> {
-            fd.write_all(&[0x1b, b'J', 40])?;
-            fd.write_all(&[0x1b, b'a', 1])?;
-            fd.write_all(b"Akri Demo for KubeCon EU 2024")?;
-            fd.write_all(&[0x1b, b'J', 175])?;
-            fd.write_all(&[0x08, 0x56, 49])?;
-            Ok(())
-        })()
-        .map_err(|_| {
-            *guard = None;
-            PrintError::NotConnected
-        })?;
-        Ok(())
-    }
-
-    pub async fn set_page(&self, width: u16, height: u16) -> Result<(), PrintError> {
-        let mut guard = self.get_guard().await?;
-        let mut fd = guard.as_ref().unwrap();
-
-        (|| -> std::io::Result<()> {
-            fd.write_all(&[0x1B, b'L', 0x1B, b'W', 0, 0, 0, 0])?;
-            fd.write_all(&width.to_le_bytes())?;
-            fd.write_all(&height.to_le_bytes())?;
-            Ok(())
-        })()
-        .map_err(|_| {
-            *guard = None;
-            PrintError::NotConnected
-        })?;
-        Ok(())
-    }
-
-    pub async fn set_font_size(&self, size: u8) -> Result<(), PrintError> {
-        if size > 8 {
-            return Err(PrintError::TooWide);
+    /// Exclusive access to the printer, connecting it when needed
+    ///
+    /// Waits at most `wait` for another job to be over, and not at all while
+    /// given up I/O still holds the device.
+    async fn get_guard(&self, wait: Duration) -> Result<Guard, PrintError> {
+        if self.abandoned.load(Ordering::Relaxed) {
+            return Err(PrintError::NotConnected);
         }
-        let size = 0x11u8 * size.saturating_sub(1);
-        let mut guard = self.get_guard().await?;
-        guard
-            .as_ref()
-            .unwrap()
-            .write_all(&[0x1d, 0x21, size])
+        let guard = tokio::time::timeout(wait, self.fd.clone().lock_owned())
+            .await
             .map_err(|_| {
-                *guard = None;
-                PrintError::NotConnected
+                if self.abandoned.load(Ordering::Relaxed) {
+                    PrintError::NotConnected
+                } else {
+                    PrintError::Busy
+                }
             })?;
-        Ok(())
+        match *guard {
+            Some(_) => Ok(guard),
+            None => self.open(guard).await,
+        }
     }
 
-    pub async fn set_position(&self, horizontal: u16, vertical: u16) -> Result<(), PrintError> {
-        let mut guard = self.get_guard().await?;
-        let mut fd = guard.as_ref().unwrap();
-        (|| -> std::io::Result<()> {
-            fd.write_all(&[0x1B, b'$'])?;
-            fd.write_all(&horizontal.to_le_bytes())?;
-            fd.write_all(&[0x1D, b'$'])?;
-            fd.write_all(&vertical.to_le_bytes())?;
-            Ok(())
-        })()
-        .map_err(|_| {
-            *guard = None;
-            PrintError::NotConnected
-        })?;
-        Ok(())
+    pub fn job(&self) -> PrintJob {
+        PrintJob::new(self.options.clone())
     }
 
-    pub async fn print_page(&self) -> Result<(), PrintError> {
-        let mut guard = self.get_guard().await?;
-        guard.as_ref().unwrap().write_all(&[0x0C]).map_err(|_| {
-            *guard = None;
-            PrintError::NotConnected
-        })?;
-        Ok(())
+    /// Send a whole job while holding exclusive access to the printer
+    pub async fn print(&self, job: &PrintJob) -> Result<(), PrintError> {
+        self.send(job.encode()).await
     }
 
-    pub async fn print_image(&self, image: &DynamicImage) -> Result<(), PrintError> {
-        let (width, height) = image.dimensions();
-        if width > 1024 {
-            return Err(PrintError::TooWide);
-        }
-        if height > 4095 {
-            return Err(PrintError::TooTall);
-        }
-        let bit_width = u16::try_from(width).unwrap().div_ceil(8);
-        let print_image: Vec<u8> = image
-            .to_rgba8()
-            .rows()
-            .flat_map(|row| {
-                let mut current_byte = 0u8;
-                let mut result = Vec::<u8>::default();
-                let need_push = (row.len() % 8) != 0;
-                for (index, pixel) in row.enumerate() {
-                    let shift = 7 - index % 8;
-                    current_byte |= ((pixel.channels()[3] > 129) as u8) << shift;
-                    if shift == 0 {
-                        result.push(current_byte);
-                        current_byte = 0;
+    /// Send already encoded commands, chunk after chunk
+    pub async fn send(&self, buf: Vec<u8>) -> Result<(), PrintError> {
+        let guard = self.get_guard(self.options.write_timeout).await?;
+        let len = buf.len();
+        self.run_blocking(guard, self.options.write_timeout, move |fd, progress| {
+            for mut chunk in buf.chunks(WRITE_CHUNK_SIZE) {
+                // A step per write, so that any progress means bytes reached the device
+                while !chunk.is_empty() {
+                    progress.check()?;
+                    match fd.write(chunk) {
+                        Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                        Ok(written) => chunk = &chunk[written..],
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
                     }
+                    progress.advance();
                 }
-                if need_push {
-                    result.push(current_byte);
-                }
-                result
-            })
-            .collect();
-        let mut guard = self.get_guard().await?;
-        let mut fd = guard.as_ref().unwrap();
-        (|| -> std::io::Result<()> {
-            fd.write_all(&[0x1D, b'v', b'0', 0])?;
-            fd.write_all(&bit_width.to_le_bytes())?;
-            fd.write_all(&u16::try_from(height).unwrap().to_le_bytes())?;
-            fd.write_all(&print_image)?;
-            Ok(())
-        })()
-        .map_err(|_| {
-            *guard = None;
-            PrintError::NotConnected
-        })?;
+            }
+            fd.flush()
+        })
+        .await?;
+        self.stats.written(len);
         Ok(())
     }
 
+    /// Feed and cut whatever is left on the paper
+    pub async fn cut(&self) -> Result<(), PrintError> {
+        self.print(self.job().cut()).await
+    }
+
     pub async fn get_status(&self) -> PrinterStatus {
-        let mut guard = match self.get_guard().await {
+        let timeout = self.options.status_timeout;
+        let guard = match self.get_guard(timeout).await {
             Ok(f) => f,
-            Err(_) => return PrinterStatus::PrinterNotConnected,
+            // Still printing a job that is making progress
+            Err(PrintError::Busy) => return *self.status.read().await,
+            Err(_) => {
+                *self.report.write().await = None;
+                return PrinterStatus::PrinterNotConnected;
+            }
         };
-        let mut fd = guard.as_ref().unwrap();
-        if fd.write_all(&[0x10, 4, 4]).is_err() {
-            *guard = None;
-            return PrinterStatus::PrinterNotConnected;
+        // The blocking side gives up on its own, the margin leaves it a chance to report why
+        let answers = self
+            .run_blocking(guard, timeout * 2, move |fd, _| {
+                let deadline = Instant::now() + timeout;
+                let mut answers = [0u8; 4];
+                for (n, answer) in StatusReport::REQUESTS.into_iter().zip(answers.iter_mut()) {
+                    fd.write_all(&Command::StatusRequest(n).to_bytes())?;
+                    *answer = read_status_byte(fd, deadline)?;
+                }
+                Ok(answers)
+            })
+            .await;
+        match answers {
+            Ok(answers) => {
+                self.stats.written(
+                    StatusReport::REQUESTS.len() * Command::StatusRequest(0).to_bytes().len(),
+                );
+                let report = StatusReport::decode(answers[0], answers[1], answers[2], answers[3]);
+                *self.report.write().await = Some(report);
+                PrinterStatus::from(&report)
+            }
+            Err(_) => {
+                *self.report.write().await = None;
+                PrinterStatus::PrinterNotConnected
+            }
         }
-        let mut status: [u8; 1] = [0];
-        let mut tries = 0;
-        loop {
-            std::thread::sleep(Duration::from_millis(10));
-            match fd.read_exact(&mut status) {
-                Ok(_) => break,
-                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {}
-                _ => panic!("Unable to read status"),
+    }
+}
+
+/// Wait for a single status byte, lp devices report nothing available as end of file
+fn read_status_byte(fd: &mut dyn Transport, deadline: Instant) -> std::io::Result<u8> {
+    let mut status: [u8; 1] = [0];
+    loop {
+        match fd.read_exact(&mut status) {
+            Ok(_) if StatusReport::is_valid_byte(status[0]) => return Ok(status[0]),
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid status byte {:#04x}", status[0]),
+                ))
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e),
+        }
+        if Instant::now() >= deadline {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, net::TcpStream, thread};
+
+    use super::*;
+
+    /// Printer on a local port, every connection being handled by `device`
+    fn fake_printer(device: fn(TcpStream)) -> Device {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                thread::spawn(move || device(stream));
             }
-            tries += 1;
-            if tries > 10 {
-                panic!("Unable to correctly read status")
+        });
+        Device::Tcp(addr.to_string())
+    }
+
+    /// Answer every byte received with `answer`
+    fn answer_each_byte(mut stream: TcpStream, answer: u8) {
+        let mut buf = [0; 64];
+        while let Ok(read @ 1..) = stream.read(&mut buf) {
+            if stream.write_all(&vec![answer; read]).is_err() {
+                return;
             }
         }
-        if status[0] & 0x60 != 0 {
-            PrinterStatus::NoPaper
-        } else if status[0] & 0x0C != 0 {
-            PrinterStatus::PaperNearEnd
-        } else {
-            PrinterStatus::Ok
+    }
+
+    /// Answer the status requests of a single poll, then hang up
+    fn one_poll(mut stream: TcpStream) {
+        let mut window = [0; 3];
+        let mut answered = 0;
+        while answered < StatusReport::REQUESTS.len() {
+            if stream.read_exact(&mut window[2..]).is_err() {
+                return;
+            }
+            if window[..2] == [0x10, 0x04] {
+                stream.write_all(&[0x12]).unwrap();
+                answered += 1;
+            }
+            window.rotate_left(1);
         }
     }
 
-    pub async fn write(&self, text: &str) -> Result<(), PrintError> {
-        let mut guard = self.get_guard().await?;
-        guard
-            .as_ref()
-            .unwrap()
-            .write_all(text.as_bytes())
-            .map_err(|_| {
-                *guard = None;
-                PrintError::NotConnected
-            })?;
-        Ok(())
+    /// Answer a single poll, then keep the connection open without reading anything
+    fn stall_after_poll(stream: TcpStream) {
+        one_poll(stream.try_clone().unwrap());
+        thread::sleep(Duration::from_secs(10));
+        drop(stream);
+    }
+
+    /// Read everything, never answering
+    fn silent(mut stream: TcpStream) {
+        let _ = std::io::copy(&mut stream, &mut std::io::sink());
+    }
+
+    fn options() -> PrinterOptions {
+        PrinterOptions {
+            status_timeout: Duration::from_millis(200),
+            write_timeout: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn oversized_jobs_are_client_errors() {
+        let code = |error: PrintError| <(StatusCode, String)>::from(error).0;
+        assert_eq!(code(PrintError::TooWide), StatusCode::BAD_REQUEST);
+        assert_eq!(code(PrintError::TooTall), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            code(PrintError::NotConnected),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(code(PrintError::Timeout), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn garbage_device_is_not_connected() {
+        let printer = Printer::new(fake_printer(|s| answer_each_byte(s, 0xFF)), options()).await;
+        assert_eq!(
+            *printer.status.read().await,
+            PrinterStatus::PrinterNotConnected
+        );
+
+        let start = Instant::now();
+        assert_eq!(
+            printer.get_status().await,
+            PrinterStatus::PrinterNotConnected
+        );
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert!(printer.report.read().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn silent_device_is_not_connected() {
+        let start = Instant::now();
+        let printer = Printer::new(fake_printer(silent), options()).await;
+        // Given up on after twice the status timeout, the read itself going on in the background
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            *printer.status.read().await,
+            PrinterStatus::PrinterNotConnected
+        );
+        assert!(printer.report.read().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn network_printer_hang_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let device = Device::Tcp(listener.local_addr().unwrap().to_string());
+        // A single connection, so that the printer cannot come back
+        thread::spawn(move || one_poll(listener.accept().unwrap().0));
+
+        let printer = Printer::new(device, options()).await;
+        assert_eq!(*printer.status.read().await, PrinterStatus::Ok);
+        assert!(printer.report.read().await.unwrap().online);
+        assert_eq!(printer.stats.connections.load(Ordering::Relaxed), 1);
+
+        assert_eq!(
+            printer.get_status().await,
+            PrinterStatus::PrinterNotConnected
+        );
+        assert!(printer.report.read().await.is_none());
+        assert_eq!(printer.connect().await, Err(PrintError::NotConnected));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stalled_write_does_not_block_the_printer() {
+        let printer = Printer::new(fake_printer(stall_after_poll), options()).await;
+        assert_eq!(*printer.status.read().await, PrinterStatus::Ok);
+
+        // Far more than the socket buffers hold
+        let result = printer.send(vec![0; 64 << 20]).await;
+        assert!(matches!(
+            result,
+            Err(PrintError::Timeout | PrintError::Incomplete)
+        ));
+
+        let start = Instant::now();
+        assert_eq!(
+            printer.get_status().await,
+            PrinterStatus::PrinterNotConnected
+        );
+        assert_eq!(printer.send(vec![0]).await, Err(PrintError::NotConnected));
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn busy_printer_keeps_its_status() {
+        let printer = Printer::new(Device::Memory(Default::default()), options()).await;
+        assert_eq!(*printer.status.read().await, PrinterStatus::Ok);
+
+        let _job = printer.fd.clone().lock_owned().await;
+        let start = Instant::now();
+        assert_eq!(printer.get_status().await, PrinterStatus::Ok);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(printer.send(vec![0]).await, Err(PrintError::Busy));
     }
 }
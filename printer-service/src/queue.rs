@@ -0,0 +1,744 @@
+//! On-disk spool of encoded print jobs and of their outcome
+//!
+//! Every job is stored as `<id>.json` holding its record and, while it
+//! waits for the printer, `<id>.bin` holding the ESC/POS bytes. The record
+//! is written after the payload so a job only exists once its payload is
+//! safely on disk. Records of finished jobs are kept for a while so callers
+//! can look them up, and so that retried submissions carrying the same
+//! idempotency key get the original job back, even across restarts. Keys
+//! are scoped by endpoint, and only replay a job built from the same request.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex, Notify};
+
+use crate::files;
+
+/// Finished jobs remembered beside the pending ones
+const HISTORY_SIZE: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Printing,
+    Printed,
+    Discarded,
+    Failed { reason: String },
+}
+
+impl JobState {
+    fn is_finished(&self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Printing)
+    }
+}
+
+/// What built a job, a heart page naming an icon or a ticket sent as is
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    #[default]
+    Heart,
+    Text,
+    Image,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub id: u64,
+    /// Records spooled before kinds were kept read as heart pages
+    #[serde(default)]
+    pub kind: JobKind,
+    /// What is being printed, e.g. the icon name
+    pub label: String,
+    #[serde(flatten)]
+    pub state: JobState,
+    /// Seconds since the epoch
+    pub submitted_at: u64,
+    pub updated_at: u64,
+    /// Client supplied key deduplicating retried submissions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// The request the key came with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<RequestDigest>,
+    /// Times the job was put back after failing to reach the printer
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+impl JobRecord {
+    /// The icon of a heart page, empty for other jobs
+    pub fn icon(&self) -> &str {
+        match self.kind {
+            JobKind::Heart => &self.label,
+            JobKind::Text | JobKind::Image => "",
+        }
+    }
+}
+
+/// Where a request was sent and what it held, a key being reused otherwise
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestDigest {
+    pub endpoint: String,
+    /// Hex encoded hash of everything the job was built from
+    pub digest: String,
+}
+
+/// A client supplied key, along with the request it came with
+#[derive(Debug, Clone)]
+pub struct Idempotency {
+    pub key: String,
+    pub request: RequestDigest,
+}
+
+/// Outcome of a submission
+pub struct Submission {
+    pub record: JobRecord,
+    /// The idempotency key matched an earlier job, nothing new was queued
+    pub replayed: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SubmitError {
+    #[error("Idempotency key {0} was used for a different request")]
+    KeyReused(String),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl From<SubmitError> for (StatusCode, String) {
+    fn from(value: SubmitError) -> Self {
+        match value {
+            SubmitError::KeyReused(_) => (StatusCode::UNPROCESSABLE_ENTITY, value.to_string()),
+            SubmitError::Io(e) => {
+                log::error!("Unable to queue job: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unable to queue job".to_owned(),
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CancelError {
+    #[error("Job not found")]
+    NotFound,
+    #[error("Job is not queued anymore")]
+    NotQueued(JobRecord),
+}
+
+struct Jobs {
+    next_id: u64,
+    pending: VecDeque<u64>,
+    records: BTreeMap<u64, JobRecord>,
+}
+
+pub struct JobQueue {
+    path: PathBuf,
+    /// How long an idempotency key is remembered, in seconds
+    key_window: u64,
+    jobs: Mutex<Jobs>,
+    wakeup: Notify,
+    /// Every record change, for live updates
+    changes: broadcast::Sender<JobRecord>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+async fn write_atomically(path: PathBuf, data: Vec<u8>) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || files::write_atomic(&path, &data))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+impl JobQueue {
+    /// Open the spool directory, picking up jobs left by a previous run
+    pub async fn open(path: PathBuf, key_window: Duration) -> std::io::Result<Self> {
+        tokio::fs::create_dir_all(&path).await?;
+        let mut records = BTreeMap::new();
+        let mut entries = tokio::fs::read_dir(&path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let entry_path = entry.path();
+            match entry_path.extension().and_then(|ext| ext.to_str()) {
+                Some("json") => {}
+                Some("bin") if !entry_path.with_extension("json").exists() => {
+                    let _ = tokio::fs::remove_file(&entry_path).await;
+                    continue;
+                }
+                Some("tmp") => {
+                    let _ = tokio::fs::remove_file(&entry_path).await;
+                    continue;
+                }
+                _ => continue,
+            }
+            let record = tokio::fs::read(&entry_path)
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|data| {
+                    serde_json::from_slice::<JobRecord>(&data).map_err(|e| e.to_string())
+                });
+            match record {
+                Ok(mut record) if !record.state.is_finished() => {
+                    if entry_path.with_extension("bin").exists() {
+                        // Interrupted while printing, start it over
+                        record.state = JobState::Queued;
+                        records.insert(record.id, record);
+                    } else {
+                        log::warn!("Dropping {}: missing payload", entry_path.display());
+                    }
+                }
+                Ok(record) => {
+                    records.insert(record.id, record);
+                }
+                Err(e) => log::warn!("Dropping {}: {}", entry_path.display(), e),
+            }
+        }
+        let pending: VecDeque<u64> = records
+            .values()
+            .filter(|record| record.state == JobState::Queued)
+            .map(|record| record.id)
+            .collect();
+        if !pending.is_empty() {
+            log::info!("Resuming {} queued jobs", pending.len());
+        }
+        Ok(Self {
+            path,
+            key_window: key_window.as_secs(),
+            jobs: Mutex::new(Jobs {
+                next_id: records.keys().next_back().map_or(1, |id| id + 1),
+                pending,
+                records,
+            }),
+            wakeup: Notify::new(),
+            changes: broadcast::channel(64).0,
+        })
+    }
+
+    fn job_path(&self, id: u64, extension: &str) -> PathBuf {
+        self.path.join(format!("{:020}.{}", id, extension))
+    }
+
+    async fn save(&self, record: &JobRecord) -> std::io::Result<()> {
+        write_atomically(
+            self.job_path(record.id, "json"),
+            serde_json::to_vec(record).map_err(std::io::Error::other)?,
+        )
+        .await
+    }
+
+    fn key_is_live(&self, record: &JobRecord, timestamp: u64) -> bool {
+        record.idempotency_key.is_some()
+            && timestamp.saturating_sub(record.submitted_at) < self.key_window
+    }
+
+    /// The live job submitted to the same endpoint with this key
+    fn find_key(
+        &self,
+        jobs: &Jobs,
+        idempotency: &Idempotency,
+    ) -> Result<Option<JobRecord>, SubmitError> {
+        let timestamp = now();
+        let Some(record) = jobs.records.values().rev().find(|record| {
+            record.idempotency_key.as_deref() == Some(&idempotency.key)
+                && record
+                    .request
+                    .as_ref()
+                    .is_some_and(|request| request.endpoint == idempotency.request.endpoint)
+                && self.key_is_live(record, timestamp)
+        }) else {
+            return Ok(None);
+        };
+        if record.request.as_ref() != Some(&idempotency.request) {
+            return Err(SubmitError::KeyReused(idempotency.key.clone()));
+        }
+        Ok(Some(record.clone()))
+    }
+
+    async fn create(
+        &self,
+        jobs: &mut Jobs,
+        kind: JobKind,
+        label: String,
+        idempotency: Option<Idempotency>,
+        state: JobState,
+    ) -> std::io::Result<JobRecord> {
+        let (idempotency_key, request) = idempotency
+            .map(|idempotency| (idempotency.key, idempotency.request))
+            .unzip();
+        let timestamp = now();
+        let record = JobRecord {
+            id: jobs.next_id,
+            kind,
+            label,
+            state,
+            submitted_at: timestamp,
+            updated_at: timestamp,
+            idempotency_key,
+            request,
+            attempts: 0,
+        };
+        self.save(&record).await?;
+        jobs.next_id += 1;
+        jobs.records.insert(record.id, record.clone());
+        let _ = self.changes.send(record.clone());
+        Ok(record)
+    }
+
+    /// The job submitted with this key, if it is still remembered
+    pub async fn lookup(
+        &self,
+        idempotency: &Idempotency,
+    ) -> Result<Option<JobRecord>, SubmitError> {
+        self.find_key(&*self.jobs.lock().await, idempotency)
+    }
+
+    fn replayed(
+        &self,
+        jobs: &Jobs,
+        idempotency: Option<&Idempotency>,
+    ) -> Result<Option<JobRecord>, SubmitError> {
+        match idempotency {
+            Some(idempotency) => self.find_key(jobs, idempotency),
+            None => Ok(None),
+        }
+    }
+
+    /// Spool a job for printing, unless its key matches an earlier job
+    pub async fn push(
+        &self,
+        kind: JobKind,
+        label: String,
+        idempotency: Option<Idempotency>,
+        data: Vec<u8>,
+    ) -> Result<Submission, SubmitError> {
+        let mut jobs = self.jobs.lock().await;
+        if let Some(record) = self.replayed(&jobs, idempotency.as_ref())? {
+            return Ok(Submission {
+                record,
+                replayed: true,
+            });
+        }
+        write_atomically(self.job_path(jobs.next_id, "bin"), data).await?;
+        let record = self
+            .create(&mut jobs, kind, label, idempotency, JobState::Queued)
+            .await?;
+        jobs.pending.push_back(record.id);
+        drop(jobs);
+        self.wake();
+        Ok(Submission {
+            record,
+            replayed: false,
+        })
+    }
+
+    /// Keep track of a job that was dropped without being spooled
+    pub async fn discard(
+        &self,
+        kind: JobKind,
+        label: String,
+        idempotency: Option<Idempotency>,
+    ) -> Result<Submission, SubmitError> {
+        let mut jobs = self.jobs.lock().await;
+        if let Some(record) = self.replayed(&jobs, idempotency.as_ref())? {
+            return Ok(Submission {
+                record,
+                replayed: true,
+            });
+        }
+        let record = self
+            .create(&mut jobs, kind, label, idempotency, JobState::Discarded)
+            .await?;
+        self.prune(&mut jobs).await;
+        Ok(Submission {
+            record,
+            replayed: false,
+        })
+    }
+
+    /// Take the oldest queued job for printing, along with its payload
+    ///
+    /// The job must then be either finished or put back with [`JobQueue::requeue`].
+    pub async fn start(&self) -> Option<(JobRecord, std::io::Result<Vec<u8>>)> {
+        let mut jobs = self.jobs.lock().await;
+        let id = *jobs.pending.front()?;
+        let record = self.update(&mut jobs, id, JobState::Printing).await?;
+        drop(jobs);
+        let data = tokio::fs::read(self.job_path(id, "bin")).await;
+        Some((record, data))
+    }
+
+    /// Put a job that could not be printed back at the head of the queue
+    pub async fn requeue(&self, id: u64) -> Option<JobRecord> {
+        let mut jobs = self.jobs.lock().await;
+        jobs.records.get_mut(&id)?.attempts += 1;
+        self.update(&mut jobs, id, JobState::Queued).await
+    }
+
+    /// Record the outcome of a job, dropping its payload
+    pub async fn finish(&self, id: u64, state: JobState) -> Option<JobRecord> {
+        let mut jobs = self.jobs.lock().await;
+        self.finish_locked(&mut jobs, id, state).await
+    }
+
+    async fn finish_locked(&self, jobs: &mut Jobs, id: u64, state: JobState) -> Option<JobRecord> {
+        jobs.pending.retain(|pending| *pending != id);
+        let record = self.update(jobs, id, state).await;
+        if let Err(e) = tokio::fs::remove_file(self.job_path(id, "bin")).await {
+            log::warn!("Unable to remove payload of job {}: {}", id, e);
+        }
+        self.prune(jobs).await;
+        record
+    }
+
+    /// Drop a job that did not start printing yet
+    pub async fn cancel(&self, id: u64) -> Result<JobRecord, CancelError> {
+        let mut jobs = self.jobs.lock().await;
+        let record = jobs.records.get(&id).ok_or(CancelError::NotFound)?;
+        if record.state != JobState::Queued {
+            return Err(CancelError::NotQueued(record.clone()));
+        }
+        self.finish_locked(&mut jobs, id, JobState::Discarded)
+            .await
+            .ok_or(CancelError::NotFound)
+    }
+
+    async fn update(&self, jobs: &mut Jobs, id: u64, state: JobState) -> Option<JobRecord> {
+        let record = jobs.records.get_mut(&id)?;
+        record.state = state;
+        record.updated_at = now();
+        let record = record.clone();
+        if let Err(e) = self.save(&record).await {
+            log::warn!("Unable to save state of job {}: {}", id, e);
+        }
+        let _ = self.changes.send(record.clone());
+        Some(record)
+    }
+
+    /// Forget the oldest finished jobs, keeping those whose key is still live
+    async fn prune(&self, jobs: &mut Jobs) {
+        let timestamp = now();
+        let finished: Vec<u64> = jobs
+            .records
+            .values()
+            .filter(|record| record.state.is_finished() && !self.key_is_live(record, timestamp))
+            .map(|record| record.id)
+            .collect();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(HISTORY_SIZE))
+        {
+            jobs.records.remove(id);
+            let _ = tokio::fs::remove_file(self.job_path(*id, "json")).await;
+        }
+    }
+
+    pub async fn get(&self, id: u64) -> Option<JobRecord> {
+        self.jobs.lock().await.records.get(&id).cloned()
+    }
+
+    /// Every known job, most recent first
+    pub async fn list(&self) -> Vec<JobRecord> {
+        self.jobs
+            .lock()
+            .await
+            .records
+            .values()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<JobRecord> {
+        self.changes.subscribe()
+    }
+
+    /// Make the queue worker check again whether it can print
+    pub fn wake(&self) {
+        self.wakeup.notify_one();
+    }
+
+    pub async fn woken(&self) {
+        self.wakeup.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    async fn push(queue: &JobQueue, label: &str) -> JobRecord {
+        let data = label.as_bytes().to_vec();
+        queue
+            .push(JobKind::Heart, label.to_owned(), None, data)
+            .await
+            .unwrap()
+            .record
+    }
+
+    #[tokio::test]
+    async fn pending_jobs_survive_a_restart_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        for label in ["first", "second", "third"] {
+            push(&queue, label).await;
+        }
+        drop(queue);
+
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        for label in ["first", "second", "third"] {
+            let (record, data) = queue.start().await.unwrap();
+            assert_eq!(record.label, label);
+            assert_eq!(record.state, JobState::Printing);
+            assert_eq!(data.unwrap(), label.as_bytes());
+            queue.finish(record.id, JobState::Printed).await;
+        }
+        assert!(queue.start().await.is_none());
+        assert_eq!(push(&queue, "fourth").await.id, 4);
+    }
+
+    #[tokio::test]
+    async fn printing_jobs_are_requeued_on_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let first = push(&queue, "first").await;
+        push(&queue, "second").await;
+        let (started, _) = queue.start().await.unwrap();
+        assert_eq!(started.id, first.id);
+        drop(queue);
+
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        assert_eq!(queue.get(first.id).await.unwrap().state, JobState::Queued);
+        let (record, data) = queue.start().await.unwrap();
+        assert_eq!(record.id, first.id);
+        assert_eq!(data.unwrap(), b"first");
+    }
+
+    #[tokio::test]
+    async fn requeued_jobs_keep_their_attempts() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let job = push(&queue, "job").await;
+        let (_, data) = queue.start().await.unwrap();
+        assert_eq!(data.unwrap(), b"job");
+        assert_eq!(queue.requeue(job.id).await.unwrap().attempts, 1);
+        drop(queue);
+
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let (record, _) = queue.start().await.unwrap();
+        assert_eq!((record.id, record.attempts), (job.id, 1));
+    }
+
+    #[tokio::test]
+    async fn kinds_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let heart = push(&queue, "text").await;
+        let text = queue
+            .push(JobKind::Text, "text".to_owned(), None, b"text".to_vec())
+            .await
+            .unwrap()
+            .record;
+        assert_eq!((heart.kind, heart.icon()), (JobKind::Heart, "text"));
+        assert_eq!((text.kind, text.icon()), (JobKind::Text, ""));
+        drop(queue);
+
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        assert_eq!(queue.get(heart.id).await.unwrap().kind, JobKind::Heart);
+        assert_eq!(queue.get(text.id).await.unwrap().kind, JobKind::Text);
+    }
+
+    #[tokio::test]
+    async fn outcomes_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let cancelled = push(&queue, "cancelled").await;
+        let printed = push(&queue, "printed").await;
+        let discarded = queue
+            .discard(JobKind::Heart, "discarded".to_owned(), None)
+            .await
+            .unwrap();
+        assert!(!discarded.replayed);
+        queue.cancel(cancelled.id).await.unwrap();
+        assert!(matches!(
+            queue.cancel(cancelled.id).await,
+            Err(CancelError::NotQueued(_))
+        ));
+        let (record, _) = queue.start().await.unwrap();
+        assert_eq!(record.id, printed.id);
+        let failed = JobState::Failed {
+            reason: "jammed".to_owned(),
+        };
+        queue.finish(printed.id, failed.clone()).await;
+        drop(queue);
+
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let state = |record: Option<JobRecord>| record.unwrap().state;
+        assert_eq!(state(queue.get(cancelled.id).await), JobState::Discarded);
+        assert_eq!(state(queue.get(printed.id).await), failed);
+        assert_eq!(
+            state(queue.get(discarded.record.id).await),
+            JobState::Discarded
+        );
+        assert!(queue.start().await.is_none());
+        assert!(matches!(queue.cancel(99).await, Err(CancelError::NotFound)));
+        // Payloads are dropped along with the jobs
+        assert!(!queue.job_path(cancelled.id, "bin").exists());
+        assert!(!queue.job_path(printed.id, "bin").exists());
+    }
+
+    #[tokio::test]
+    async fn leftovers_are_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let job = push(&queue, "job").await;
+        let orphan = queue.job_path(7, "bin");
+        let partial = dir.path().join("00000000000000000008.json.0.tmp");
+        drop(queue);
+        std::fs::write(&orphan, b"orphan").unwrap();
+        std::fs::write(&partial, b"{").unwrap();
+        std::fs::write(dir.path().join("00000000000000000009.json"), b"{").unwrap();
+
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        assert!(!orphan.exists());
+        assert!(!partial.exists());
+        let ids: Vec<u64> = queue.list().await.iter().map(|record| record.id).collect();
+        assert_eq!(ids, [job.id]);
+    }
+
+    fn keyed(endpoint: &str, digest: &str) -> Option<Idempotency> {
+        Some(Idempotency {
+            key: "retry-me".to_owned(),
+            request: RequestDigest {
+                endpoint: endpoint.to_owned(),
+                digest: digest.to_owned(),
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn keys_replay_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let first = queue
+            .push(
+                JobKind::Heart,
+                "heart".to_owned(),
+                keyed("love", "a"),
+                b"job".to_vec(),
+            )
+            .await
+            .unwrap();
+        assert!(!first.replayed);
+        let again = queue
+            .push(
+                JobKind::Heart,
+                "heart".to_owned(),
+                keyed("love", "a"),
+                b"job".to_vec(),
+            )
+            .await
+            .unwrap();
+        assert!(again.replayed);
+        assert_eq!(again.record.id, first.record.id);
+        drop(queue);
+
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let found = queue.lookup(&keyed("love", "a").unwrap()).await.unwrap();
+        assert_eq!(found.unwrap().id, first.record.id);
+        let again = queue
+            .discard(JobKind::Heart, "heart".to_owned(), keyed("love", "a"))
+            .await;
+        assert_eq!(again.unwrap().record.id, first.record.id);
+        assert_eq!(queue.list().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_are_scoped_by_endpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        let love = queue
+            .discard(JobKind::Heart, "heart".to_owned(), keyed("love", "a"))
+            .await
+            .unwrap();
+        assert!(queue
+            .lookup(&keyed("text", "a").unwrap())
+            .await
+            .unwrap()
+            .is_none());
+        let text = queue
+            .push(
+                JobKind::Text,
+                "text".to_owned(),
+                keyed("text", "a"),
+                b"text".to_vec(),
+            )
+            .await
+            .unwrap();
+        assert!(!text.replayed);
+        assert_ne!(text.record.id, love.record.id);
+    }
+
+    #[tokio::test]
+    async fn reused_keys_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), WINDOW).await.unwrap();
+        queue
+            .push(
+                JobKind::Text,
+                "text".to_owned(),
+                keyed("text", "a"),
+                b"a".to_vec(),
+            )
+            .await
+            .unwrap();
+        let reused = queue
+            .push(
+                JobKind::Text,
+                "text".to_owned(),
+                keyed("text", "b"),
+                b"b".to_vec(),
+            )
+            .await;
+        assert!(matches!(reused, Err(SubmitError::KeyReused(key)) if key == "retry-me"));
+        assert!(matches!(
+            queue.lookup(&keyed("text", "b").unwrap()).await,
+            Err(SubmitError::KeyReused(_))
+        ));
+        assert_eq!(queue.list().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path().to_owned(), Duration::ZERO)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let submission = queue
+                .push(
+                    JobKind::Text,
+                    "text".to_owned(),
+                    keyed("text", "a"),
+                    b"a".to_vec(),
+                )
+                .await
+                .unwrap();
+            assert!(!submission.replayed);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! Conversion of images to the 1 bit per dot bitmaps printers understand
+
+use std::str::FromStr;
+
+use image::{DynamicImage, GenericImageView, GrayImage, Luma};
+use serde::{Deserialize, Serialize};
+
+use crate::escpos::Bitmap;
+
+/// How gray levels are turned into black and white dots
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dithering {
+    /// Black below 50% luminance, best for logos and line art
+    #[default]
+    Threshold,
+    FloydSteinberg,
+    /// Diffuses only 3/4 of the error, keeps more contrast than Floyd-Steinberg
+    Atkinson,
+    /// Ordered dithering with a 4x4 Bayer matrix
+    Bayer,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown dithering mode: {0}")]
+pub struct UnknownDithering(String);
+
+impl FromStr for Dithering {
+    type Err = UnknownDithering;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threshold" => Ok(Dithering::Threshold),
+            "floyd-steinberg" => Ok(Dithering::FloydSteinberg),
+            "atkinson" => Ok(Dithering::Atkinson),
+            "bayer" => Ok(Dithering::Bayer),
+            _ => Err(UnknownDithering(s.to_owned())),
+        }
+    }
+}
+
+const BAYER_4X4: [[f32; 4]; 4] = [
+    [0.0, 8.0, 2.0, 10.0],
+    [12.0, 4.0, 14.0, 6.0],
+    [3.0, 11.0, 1.0, 9.0],
+    [15.0, 7.0, 13.0, 5.0],
+];
+
+/// Luminance of every pixel once composited onto white paper, from 0 to 255
+fn luminance(image: &DynamicImage) -> Vec<f32> {
+    image
+        .to_rgba8()
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b, a] = pixel.0.map(f32::from);
+            let alpha = a / 255.0;
+            let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+            luma * alpha + 255.0 * (1.0 - alpha)
+        })
+        .collect()
+}
+
+/// Spread the quantization error of every pixel to the ones not visited yet
+fn diffuse(levels: &mut [f32], width: usize, kernel: &[(isize, usize, f32)]) -> Vec<bool> {
+    if width == 0 {
+        return Vec::new();
+    }
+    let height = levels.len() / width;
+    let mut black = vec![false; levels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let index = y * width + x;
+            let old = levels[index];
+            let new = if old < 128.0 { 0.0 } else { 255.0 };
+            black[index] = new == 0.0;
+            let error = old - new;
+            for (dx, dy, weight) in kernel {
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx >= 0 && (nx as usize) < width && ny < height {
+                    levels[ny * width + nx as usize] += error * weight;
+                }
+            }
+        }
+    }
+    black
+}
+
+fn pack(width: usize, height: usize, black: &[bool]) -> Bitmap {
+    let width_bytes = width.div_ceil(8);
+    let mut data = vec![0u8; width_bytes * height];
+    for y in 0..height {
+        for x in 0..width {
+            if black[y * width + x] {
+                data[y * width_bytes + x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+    }
+    Bitmap {
+        width: width as u16,
+        height: height as u16,
+        data,
+    }
+}
+
+/// Rasterize an image, transparent areas being left blank
+pub fn rasterize(image: &DynamicImage, dithering: Dithering) -> Bitmap {
+    let (width, height) = image.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    if width == 0 || height == 0 {
+        return pack(width, height, &[]);
+    }
+    let mut levels = luminance(image);
+    let black = match dithering {
+        Dithering::Threshold => levels.iter().map(|level| *level < 128.0).collect(),
+        Dithering::FloydSteinberg => diffuse(
+            &mut levels,
+            width,
+            &[
+                (1, 0, 7.0 / 16.0),
+                (-1, 1, 3.0 / 16.0),
+                (0, 1, 5.0 / 16.0),
+                (1, 1, 1.0 / 16.0),
+            ],
+        ),
+        Dithering::Atkinson => diffuse(
+            &mut levels,
+            width,
+            &[
+                (1, 0, 1.0 / 8.0),
+                (2, 0, 1.0 / 8.0),
+                (-1, 1, 1.0 / 8.0),
+                (0, 1, 1.0 / 8.0),
+                (1, 1, 1.0 / 8.0),
+                (0, 2, 1.0 / 8.0),
+            ],
+        ),
+        Dithering::Bayer => levels
+            .iter()
+            .enumerate()
+            .map(|(index, level)| {
+                let threshold = (BAYER_4X4[(index / width) % 4][index % width % 4] + 0.5) * 16.0;
+                *level < threshold
+            })
+            .collect(),
+    };
+    pack(width, height, &black)
+}
+
+/// Lighter than this once composited onto white, a pixel counts as blank paper
+const BLANK_LEVEL: f32 = 250.0;
+
+/// Crop away blank borders, images without any ink are returned unchanged
+pub fn trim(image: &DynamicImage) -> DynamicImage {
+    let width = image.width() as usize;
+    let levels = luminance(image);
+    let inked = levels
+        .iter()
+        .enumerate()
+        .filter(|(_, level)| **level < BLANK_LEVEL)
+        .map(|(index, _)| (index % width, index / width));
+    let bounds = inked.fold(None, |bounds, (x, y)| match bounds {
+        None => Some((x, y, x, y)),
+        Some((left, top, right, bottom)) => {
+            Some((left.min(x), top.min(y), right.max(x), bottom.max(y)))
+        }
+    });
+    match bounds {
+        Some((left, top, right, bottom)) => image.crop_imm(
+            left as u32,
+            top as u32,
+            (right - left + 1) as u32,
+            (bottom - top + 1) as u32,
+        ),
+        None => image.clone(),
+    }
+}
+
+/// What a bitmap looks like on paper, black dots on white
+pub fn preview(bitmap: &Bitmap) -> GrayImage {
+    let width_bytes = bitmap.width_bytes() as usize;
+    GrayImage::from_fn(u32::from(bitmap.width), u32::from(bitmap.height), |x, y| {
+        let byte = bitmap.data[y as usize * width_bytes + x as usize / 8];
+        if byte & (0x80 >> (x % 8)) != 0 {
+            Luma([0])
+        } else {
+            Luma([255])
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    /// Gray ramp on top, black fading from opaque to transparent below
+    fn sample() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 32, |x, y| {
+            let level = (x * 255 / 63) as u8;
+            if y < 16 {
+                Rgba([level, level, level, 255])
+            } else {
+                Rgba([0, 0, 0, 255 - level])
+            }
+        }))
+    }
+
+    /// Compare with `testdata/raster/<name>.png`, rewritten when `UPDATE_GOLDEN` is set
+    fn check_golden(name: &str, bitmap: &Bitmap) {
+        let path = format!(
+            "{}/testdata/raster/{}.png",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        );
+        let actual = preview(bitmap);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            actual.save(&path).unwrap();
+        }
+        let expected = image::open(&path).unwrap().to_luma8();
+        assert!(expected == actual, "{} differs from {}", name, path);
+    }
+
+    #[test]
+    fn golden_threshold() {
+        check_golden("threshold", &rasterize(&sample(), Dithering::Threshold));
+    }
+
+    #[test]
+    fn golden_floyd_steinberg() {
+        check_golden(
+            "floyd-steinberg",
+            &rasterize(&sample(), Dithering::FloydSteinberg),
+        );
+    }
+
+    #[test]
+    fn golden_atkinson() {
+        check_golden("atkinson", &rasterize(&sample(), Dithering::Atkinson));
+    }
+
+    #[test]
+    fn golden_bayer() {
+        check_golden("bayer", &rasterize(&sample(), Dithering::Bayer));
+    }
+
+    #[test]
+    fn alpha_is_composited_onto_white() {
+        let pixel =
+            |color: [u8; 4]| DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 1, Rgba(color)));
+        for dithering in [
+            Dithering::Threshold,
+            Dithering::FloydSteinberg,
+            Dithering::Atkinson,
+            Dithering::Bayer,
+        ] {
+            assert_eq!(rasterize(&pixel([0, 0, 0, 0]), dithering).data, [0x00]);
+            assert_eq!(rasterize(&pixel([0, 0, 0, 255]), dithering).data, [0xFF]);
+            assert_eq!(
+                rasterize(&pixel([255, 255, 255, 255]), dithering).data,
+                [0x00]
+            );
+        }
+    }
+
+    #[test]
+    fn rows_are_padded() {
+        let image = DynamicImage::ImageLuma8(GrayImage::from_pixel(10, 2, Luma([0])));
+        let bitmap = rasterize(&image, Dithering::Threshold);
+        assert_eq!((bitmap.width, bitmap.height), (10, 2));
+        assert_eq!(bitmap.data, [0xFF, 0xC0, 0xFF, 0xC0]);
+    }
+
+    #[test]
+    fn empty_images() {
+        for (width, height) in [(0, 0), (0, 5), (5, 0)] {
+            let image = DynamicImage::new_luma8(width, height);
+            for dithering in [
+                Dithering::Threshold,
+                Dithering::FloydSteinberg,
+                Dithering::Atkinson,
+                Dithering::Bayer,
+            ] {
+                let bitmap = rasterize(&image, dithering);
+                assert_eq!(bitmap.width, width as u16);
+                assert!(bitmap.data.is_empty());
+            }
+        }
+        assert!(diffuse(&mut [], 0, &[(1, 0, 1.0)]).is_empty());
+    }
+
+    #[test]
+    fn dithering_names() {
+        assert_eq!("bayer".parse::<Dithering>().unwrap(), Dithering::Bayer);
+        assert_eq!(
+            "floyd-steinberg".parse::<Dithering>().unwrap(),
+            Dithering::FloydSteinberg
+        );
+        assert!("dither".parse::<Dithering>().is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::virtual_printer::{VirtualPrinter, PAPER_WIDTH};
+
+/// Anything the printer can exchange ESC/POS bytes with
+pub trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send + ?Sized> Transport for T {}
+
+const DEFAULT_BAUD_RATE: u32 = 9600;
+const DEFAULT_RAW_PORT: u16 = 9100;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+const KEEPALIVE_TIME: Duration = Duration::from_secs(10);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where the printer is reachable, parsed from `<kind>:<target>`
+///
+/// A bare path is understood as a Linux lp device.
+#[derive(Debug, Clone)]
+pub enum Device {
+    /// Linux USB printer class device, e.g. `/dev/usb/lp0`
+    Lp(PathBuf),
+    /// Raw socket, e.g. `tcp:192.168.1.20:9100`, port defaults to 9100
+    Tcp(String),
+    /// Serial port, e.g. `serial:/dev/ttyUSB0@115200`
+    Serial { path: PathBuf, baud_rate: u32 },
+    /// Append everything sent to the printer to a file, e.g. `capture:/tmp/job.bin`
+    Capture(PathBuf),
+    /// Keep everything sent to the printer in memory
+    Memory(Arc<Mutex<Vec<u8>>>),
+    /// Render tickets as PNG files in a directory, e.g. `virtual:/tmp/tickets`
+    Virtual(PathBuf),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceParseError {
+    #[error("Unknown device kind: {0}")]
+    UnknownKind(String),
+    #[error("Missing target for device kind {0}")]
+    MissingTarget(String),
+    #[error("Invalid baud rate: {0}")]
+    InvalidBaudRate(String),
+}
+
+impl FromStr for Device {
+    type Err = DeviceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((kind, target)) = s.split_once(':') else {
+            if s == "memory" {
+                return Ok(Device::Memory(Default::default()));
+            }
+            return Ok(Device::Lp(PathBuf::from(s)));
+        };
+        if kind != "memory" && target.is_empty() {
+            return Err(DeviceParseError::MissingTarget(kind.to_owned()));
+        }
+        match kind {
+            "lp" => Ok(Device::Lp(PathBuf::from(target))),
+            "tcp" if target.ends_with(']') || !target.contains(':') => {
+                Ok(Device::Tcp(format!("{}:{}", target, DEFAULT_RAW_PORT)))
+            }
+            "tcp" => Ok(Device::Tcp(target.to_owned())),
+            "serial" => {
+                let (path, baud_rate) = match target.rsplit_once('@') {
+                    Some((path, baud)) => (
+                        path,
+                        baud.parse()
+                            .map_err(|_| DeviceParseError::InvalidBaudRate(baud.to_owned()))?,
+                    ),
+                    None => (target, DEFAULT_BAUD_RATE),
+                };
+                Ok(Device::Serial {
+                    path: PathBuf::from(path),
+                    baud_rate,
+                })
+            }
+            "capture" => Ok(Device::Capture(PathBuf::from(target))),
+            "memory" => Ok(Device::Memory(Default::default())),
+            "virtual" => Ok(Device::Virtual(PathBuf::from(target))),
+            _ => Err(DeviceParseError::UnknownKind(kind.to_owned())),
+        }
+    }
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Device::Lp(path) => write!(f, "lp:{}", path.display()),
+            Device::Tcp(addr) => write!(f, "tcp:{}", addr),
+            Device::Serial { path, baud_rate } => {
+                write!(f, "serial:{}@{}", path.display(), baud_rate)
+            }
+            Device::Capture(path) => write!(f, "capture:{}", path.display()),
+            Device::Memory(_) => write!(f, "memory:"),
+            Device::Virtual(path) => write!(f, "virtual:{}", path.display()),
+        }
+    }
+}
+
+impl Device {
+    pub fn open(&self) -> std::io::Result<Box<dyn Transport>> {
+        Ok(match self {
+            Device::Lp(path) => Box::new(File::options().write(true).read(true).open(path)?),
+            Device::Tcp(addr) => Box::new(NetworkPrinter::connect(addr)?),
+            Device::Serial { path, baud_rate } => Box::new(
+                serialport::new(path.to_string_lossy(), *baud_rate)
+                    .timeout(Duration::from_millis(100))
+                    .open()?,
+            ),
+            Device::Capture(path) => Box::new(CaptureSink::new(
+                File::options().create(true).append(true).open(path)?,
+            )),
+            Device::Memory(buffer) => Box::new(CaptureSink::new(SharedBuffer(buffer.clone()))),
+            Device::Virtual(path) => {
+                std::fs::create_dir_all(path)?;
+                Box::new(VirtualPrinter::new(PAPER_WIDTH, Some(path.clone())))
+            }
+        })
+    }
+}
+
+/// Printer listening for raw jobs on a TCP socket (JetDirect/AppSocket)
+///
+/// Dead peers are detected through TCP keepalive, and a closed connection
+/// surfaces as an error on read instead of an endless end of file.
+pub struct NetworkPrinter {
+    stream: TcpStream,
+}
+
+impl NetworkPrinter {
+    pub fn connect(addr: &str) -> std::io::Result<Self> {
+        let mut last_error = std::io::Error::new(
+            ErrorKind::AddrNotAvailable,
+            format!("{} did not resolve to any address", addr),
+        );
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+                    stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+                    socket2::SockRef::from(&stream).set_tcp_keepalive(
+                        &socket2::TcpKeepalive::new()
+                            .with_time(KEEPALIVE_TIME)
+                            .with_interval(KEEPALIVE_INTERVAL),
+                    )?;
+                    return Ok(Self { stream });
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl Read for NetworkPrinter {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.stream.read(buf)? {
+            0 if !buf.is_empty() => Err(ErrorKind::ConnectionAborted.into()),
+            read => Ok(read),
+        }
+    }
+}
+
+impl Write for NetworkPrinter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A `Vec<u8>` that can be looked at while the printer owns the transport
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sink recording every byte written to it
+///
+/// It behaves like a printer that is always ready: every real-time status
+/// request (DLE EOT n) gets answered with a "no error" status byte.
+pub struct CaptureSink<W: Write> {
+    inner: W,
+    // Last bytes written, kept to spot status requests split across writes
+    tail: Vec<u8>,
+    replies: VecDeque<u8>,
+}
+
+impl<W: Write> CaptureSink<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            tail: Vec::with_capacity(2),
+            replies: VecDeque::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for CaptureSink<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.tail.extend_from_slice(&buf[..written]);
+        for window in self.tail.windows(3) {
+            if window[0] == 0x10 && window[1] == 0x04 {
+                self.replies.push_back(0x12);
+            }
+        }
+        let keep = self.tail.len().min(2);
+        self.tail.drain(..self.tail.len() - keep);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Read for CaptureSink<W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.replies.pop_front() {
+                Some(byte) => {
+                    buf[read] = byte;
+                    read += 1;
+                }
+                None => break,
+            }
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    #[test]
+    fn tcp_device_defaults_to_raw_port() {
+        let device: Device = "tcp:192.168.1.20".parse().unwrap();
+        assert_eq!(device.to_string(), "tcp:192.168.1.20:9100");
+        let device: Device = "tcp:[::1]".parse().unwrap();
+        assert_eq!(device.to_string(), "tcp:[::1]:9100");
+        let device: Device = "tcp:localhost:9101".parse().unwrap();
+        assert_eq!(device.to_string(), "tcp:localhost:9101");
+    }
+
+    #[test]
+    fn network_printer_round_trip_and_hang_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 3];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&[0x16]).unwrap();
+            request
+        });
+
+        let mut printer = NetworkPrinter::connect(&addr).unwrap();
+        printer.write_all(&[0x10, 0x04, 0x01]).unwrap();
+        let mut answer = [0];
+        printer.read_exact(&mut answer).unwrap();
+        assert_eq!(answer, [0x16]);
+        assert_eq!(peer.join().unwrap(), [0x10, 0x04, 0x01]);
+
+        let error = printer.read(&mut answer).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionAborted);
+    }
+
+    #[test]
+    fn network_printer_refused() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert!(NetworkPrinter::connect(&addr.to_string()).is_err());
+    }
+}
@@ -0,0 +1,723 @@
+//! Software ESC/POS printer, rendering the tickets it receives to PNG
+//!
+//! Only the subset of ESC/POS the service emits is interpreted, anything
+//! else is skipped. Text is drawn in a 12x24 dots cell to match font A of
+//! the real printers.
+
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+use image::GrayImage;
+
+use crate::escpos::{Bitmap, Justification};
+
+/// 80mm paper at 203 dpi
+pub const PAPER_WIDTH: u32 = 576;
+pub const FONT_WIDTH: u32 = 12;
+pub const FONT_HEIGHT: u32 = 24;
+const LINE_SPACING: u32 = 30;
+/// Tallest page mode area of the usual 80mm printers
+pub const MAX_PAGE_HEIGHT: u32 = 1662;
+
+const ESC: u8 = 0x1B;
+const GS: u8 = 0x1D;
+const DLE: u8 = 0x10;
+const EOT: u8 = 0x04;
+const LF: u8 = 0x0A;
+const FF: u8 = 0x0C;
+
+/// Sensors reported through DLE EOT
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtualStatus {
+    pub paper_near_end: bool,
+    pub paper_out: bool,
+    pub cover_open: bool,
+    pub cutter_error: bool,
+}
+
+impl VirtualStatus {
+    fn byte(&self, n: u8) -> u8 {
+        let offline = self.paper_out || self.cover_open || self.cutter_error;
+        let mut status = 0x12;
+        match n {
+            1 if offline => status |= 0x08,
+            2 => {
+                if self.cover_open {
+                    status |= 0x04;
+                }
+                if self.paper_out {
+                    status |= 0x20;
+                }
+                if self.cutter_error {
+                    status |= 0x40;
+                }
+            }
+            3 if self.cutter_error => status |= 0x08,
+            4 => {
+                if self.paper_near_end {
+                    status |= 0x0C;
+                }
+                if self.paper_out {
+                    status |= 0x60;
+                }
+            }
+            _ => {}
+        }
+        status
+    }
+}
+
+/// White background grayscale surface that can grow downward
+struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![255; (width * height) as usize],
+        }
+    }
+
+    fn grow_to(&mut self, height: u32) {
+        if height > self.height {
+            self.height = height;
+            self.pixels.resize((self.width * height) as usize, 255);
+        }
+    }
+
+    fn set_black(&mut self, x: i32, y: i32) {
+        if x >= 0 && y >= 0 && (x as u32) < self.width && (y as u32) < self.height {
+            self.pixels[(y as u32 * self.width + x as u32) as usize] = 0;
+        }
+    }
+
+    fn draw_bitmap(&mut self, bitmap: &Bitmap, x: u32, y: u32) {
+        let width_bytes = bitmap.width_bytes() as usize;
+        for row in 0..bitmap.height as usize {
+            for column in 0..bitmap.width as usize {
+                let byte = bitmap.data[row * width_bytes + column / 8];
+                if byte & (0x80 >> (column % 8)) != 0 {
+                    self.set_black((x as usize + column) as i32, (y as usize + row) as i32);
+                }
+            }
+        }
+    }
+
+    fn draw_text(&mut self, text: &[u8], x: u32, y: u32, style: TextStyle) {
+        let font = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+        let magnification = style.magnification;
+        for (index, byte) in text.iter().enumerate() {
+            let mut cell = ScaledCell {
+                canvas: self,
+                origin: (x + index as u32 * FONT_WIDTH * magnification.0, y),
+                magnification,
+            };
+            let glyph = (*byte as char).to_string();
+            // Glyphs are 10x20, center them in the 12x24 cell
+            let _ =
+                Text::with_baseline(&glyph, Point::new(1, 2), font, Baseline::Top).draw(&mut cell);
+            if style.bold {
+                // Emphasis strikes every dot twice, one dot apart
+                let _ = Text::with_baseline(&glyph, Point::new(2, 2), font, Baseline::Top)
+                    .draw(&mut cell);
+            }
+        }
+        if style.underline {
+            let bottom = y + FONT_HEIGHT * magnification.1 - 1;
+            for column in 0..text.len() as u32 * FONT_WIDTH * magnification.0 {
+                self.set_black((x + column) as i32, bottom as i32);
+            }
+        }
+    }
+
+    fn blit(&mut self, other: &Canvas, x: u32, y: u32) {
+        self.grow_to(y + other.height);
+        for row in 0..other.height {
+            for column in 0..other.width.min(self.width.saturating_sub(x)) {
+                let pixel = other.pixels[(row * other.width + column) as usize];
+                self.pixels[((y + row) * self.width + x + column) as usize] &= pixel;
+            }
+        }
+    }
+
+    fn into_image(self) -> GrayImage {
+        GrayImage::from_raw(self.width, self.height, self.pixels).unwrap()
+    }
+}
+
+/// Character settings applying to the text that follows
+#[derive(Debug, Clone, Copy)]
+struct TextStyle {
+    magnification: (u32, u32),
+    bold: bool,
+    underline: bool,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            magnification: (1, 1),
+            bold: false,
+            underline: false,
+        }
+    }
+}
+
+/// Draw target mapping every font pixel to a magnified block of the canvas
+struct ScaledCell<'a> {
+    canvas: &'a mut Canvas,
+    origin: (u32, u32),
+    magnification: (u32, u32),
+}
+
+impl OriginDimensions for ScaledCell<'_> {
+    fn size(&self) -> Size {
+        Size::new(FONT_WIDTH, FONT_HEIGHT)
+    }
+}
+
+impl DrawTarget for ScaledCell<'_> {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let (mw, mh) = self.magnification;
+        for Pixel(point, color) in pixels {
+            if color.is_off() || point.x < 0 || point.y < 0 {
+                continue;
+            }
+            for dy in 0..mh {
+                for dx in 0..mw {
+                    self.canvas.set_black(
+                        (self.origin.0 + point.x as u32 * mw + dx) as i32,
+                        (self.origin.1 + point.y as u32 * mh + dy) as i32,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Page {
+    x: u32,
+    y: u32,
+    area: (u32, u32),
+    canvas: Canvas,
+}
+
+/// Printer state machine fed with raw ESC/POS bytes
+///
+/// Every cut closes the current ticket, which is either written as a PNG
+/// in the output directory or kept until [`VirtualPrinter::take_tickets`].
+pub struct VirtualPrinter {
+    paper_width: u32,
+    output_dir: Option<PathBuf>,
+    pub status: VirtualStatus,
+    pending: Vec<u8>,
+    replies: VecDeque<u8>,
+    roll: Canvas,
+    roll_y: u32,
+    line: Vec<(Vec<u8>, TextStyle)>,
+    style: TextStyle,
+    justification: Justification,
+    page: Option<Page>,
+    tickets: Vec<GrayImage>,
+    printed: usize,
+}
+
+enum Parsed {
+    Incomplete,
+    Consumed(usize),
+}
+
+impl VirtualPrinter {
+    pub fn new(paper_width: u32, output_dir: Option<PathBuf>) -> Self {
+        Self {
+            paper_width,
+            output_dir,
+            status: VirtualStatus::default(),
+            pending: Vec::new(),
+            replies: VecDeque::new(),
+            roll: Canvas::new(paper_width, 0),
+            roll_y: 0,
+            line: Vec::new(),
+            style: TextStyle::default(),
+            justification: Justification::Left,
+            page: None,
+            tickets: Vec::new(),
+            printed: 0,
+        }
+    }
+
+    /// Render a complete job, returning every ticket including an uncut one
+    pub fn render(paper_width: u32, data: &[u8]) -> Vec<GrayImage> {
+        let mut printer = Self::new(paper_width, None);
+        printer.feed(data);
+        if printer.page.is_some() {
+            printer.print_page();
+        }
+        printer.finish_ticket();
+        printer.take_tickets()
+    }
+
+    pub fn take_tickets(&mut self) -> Vec<GrayImage> {
+        std::mem::take(&mut self.tickets)
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        let mut offset = 0;
+        while offset < self.pending.len() {
+            match self.parse(offset) {
+                Parsed::Consumed(length) => offset += length,
+                Parsed::Incomplete => break,
+            }
+        }
+        self.pending.drain(..offset);
+    }
+
+    fn reset(&mut self) {
+        self.line.clear();
+        self.style = TextStyle::default();
+        self.justification = Justification::Left;
+        self.page = None;
+    }
+
+    fn parse(&mut self, offset: usize) -> Parsed {
+        let buf = &self.pending[offset..];
+        let arg = |index: usize| buf.get(index).copied();
+        let word = |index: usize| Some(u16::from_le_bytes([arg(index)?, arg(index + 1)?]) as u32);
+        macro_rules! need {
+            ($value:expr) => {
+                match $value {
+                    Some(value) => value,
+                    None => return Parsed::Incomplete,
+                }
+            };
+        }
+        match buf[0] {
+            LF => {
+                self.flush_line(LINE_SPACING);
+                Parsed::Consumed(1)
+            }
+            FF => {
+                self.print_page();
+                Parsed::Consumed(1)
+            }
+            DLE => match need!(arg(1)) {
+                EOT => {
+                    let n = need!(arg(2));
+                    self.replies.push_back(self.status.byte(n));
+                    Parsed::Consumed(3)
+                }
+                _ => Parsed::Consumed(2),
+            },
+            ESC => match need!(arg(1)) {
+                b'@' => {
+                    self.reset();
+                    Parsed::Consumed(2)
+                }
+                b'L' => {
+                    self.page = Some(Page {
+                        x: 0,
+                        y: 0,
+                        area: (0, 0),
+                        canvas: Canvas::new(self.paper_width, 0),
+                    });
+                    Parsed::Consumed(2)
+                }
+                b'W' => {
+                    let (x, y) = (need!(word(2)), need!(word(4)));
+                    let (width, height) = (need!(word(6)), need!(word(8)));
+                    if let Some(page) = self.page.as_mut() {
+                        // Real printers clip the area the same way instead of allocating it
+                        let x = x.min(self.paper_width);
+                        page.area = (x, y.min(MAX_PAGE_HEIGHT));
+                        page.canvas = Canvas::new(
+                            width.min(self.paper_width - x),
+                            height.min(MAX_PAGE_HEIGHT - page.area.1),
+                        );
+                    }
+                    Parsed::Consumed(10)
+                }
+                b'$' => {
+                    let position = need!(word(2));
+                    if let Some(page) = self.page.as_mut() {
+                        page.x = position;
+                    }
+                    Parsed::Consumed(4)
+                }
+                b'J' => {
+                    let dots = need!(arg(2));
+                    self.flush_line(dots as u32);
+                    Parsed::Consumed(3)
+                }
+                b'a' => {
+                    self.justification = match need!(arg(2)) {
+                        1 | b'1' => Justification::Center,
+                        2 | b'2' => Justification::Right,
+                        _ => Justification::Left,
+                    };
+                    Parsed::Consumed(3)
+                }
+                b'E' => {
+                    self.style.bold = need!(arg(2)) & 0x01 != 0;
+                    Parsed::Consumed(3)
+                }
+                b'-' => {
+                    self.style.underline = matches!(need!(arg(2)), 1 | 2 | b'1' | b'2');
+                    Parsed::Consumed(3)
+                }
+                b'!' | b'G' | b'M' | b'T' | b'd' | b'3' | b't' => {
+                    need!(arg(2));
+                    Parsed::Consumed(3)
+                }
+                other => {
+                    log::debug!("Skipping unsupported command ESC {:#04x}", other);
+                    Parsed::Consumed(2)
+                }
+            },
+            GS => match need!(arg(1)) {
+                b'!' => {
+                    let size = need!(arg(2));
+                    self.style.magnification = ((size >> 4) as u32 + 1, (size & 0x0F) as u32 + 1);
+                    Parsed::Consumed(3)
+                }
+                b'$' => {
+                    let position = need!(word(2));
+                    if let Some(page) = self.page.as_mut() {
+                        page.y = position;
+                    }
+                    Parsed::Consumed(4)
+                }
+                b'v' => {
+                    let width_bytes = need!(word(4));
+                    let height = need!(word(6));
+                    let length = 8 + (width_bytes * height) as usize;
+                    if buf.len() < length {
+                        return Parsed::Incomplete;
+                    }
+                    let bitmap = Bitmap {
+                        width: (width_bytes * 8) as u16,
+                        height: height as u16,
+                        data: buf[8..length].to_vec(),
+                    };
+                    self.print_bitmap(&bitmap);
+                    Parsed::Consumed(length)
+                }
+                b'V' => match need!(arg(2)) {
+                    b'A' | b'B' => {
+                        need!(arg(3));
+                        self.cut();
+                        Parsed::Consumed(4)
+                    }
+                    _ => {
+                        self.cut();
+                        Parsed::Consumed(3)
+                    }
+                },
+                b'B' => {
+                    need!(arg(2));
+                    Parsed::Consumed(3)
+                }
+                b'L' | b'W' => {
+                    need!(word(2));
+                    Parsed::Consumed(4)
+                }
+                other => {
+                    log::debug!("Skipping unsupported command GS {:#04x}", other);
+                    Parsed::Consumed(2)
+                }
+            },
+            byte if byte < 0x20 => Parsed::Consumed(1),
+            _ => {
+                let length = buf
+                    .iter()
+                    .position(|byte| *byte < 0x20)
+                    .unwrap_or(buf.len());
+                let text = buf[..length].to_vec();
+                self.print_text(text);
+                Parsed::Consumed(length)
+            }
+        }
+    }
+
+    fn print_text(&mut self, text: Vec<u8>) {
+        match self.page.as_mut() {
+            Some(page) => {
+                let (mw, mh) = self.style.magnification;
+                let top = page.y.saturating_sub(FONT_HEIGHT * mh);
+                page.canvas.draw_text(&text, page.x, top, self.style);
+                page.x += text.len() as u32 * FONT_WIDTH * mw;
+            }
+            None => self.line.push((text, self.style)),
+        }
+    }
+
+    fn print_bitmap(&mut self, bitmap: &Bitmap) {
+        match self.page.as_mut() {
+            Some(page) => page.canvas.draw_bitmap(bitmap, page.x, page.y),
+            None => {
+                self.flush_line(0);
+                let x = self.justified_x(bitmap.width as u32);
+                self.roll.grow_to(self.roll_y + bitmap.height as u32);
+                self.roll.draw_bitmap(bitmap, x, self.roll_y);
+                self.roll_y += bitmap.height as u32;
+            }
+        }
+    }
+
+    fn justified_x(&self, width: u32) -> u32 {
+        match self.justification {
+            Justification::Left => 0,
+            Justification::Center => self.paper_width.saturating_sub(width) / 2,
+            Justification::Right => self.paper_width.saturating_sub(width),
+        }
+    }
+
+    /// Print the standard mode line buffer and feed the paper
+    fn flush_line(&mut self, feed: u32) {
+        if self.page.is_some() {
+            return;
+        }
+        let line = std::mem::take(&mut self.line);
+        if line.is_empty() {
+            self.roll_y += feed;
+            self.roll.grow_to(self.roll_y);
+            return;
+        }
+        let width: u32 = line
+            .iter()
+            .map(|(text, style)| text.len() as u32 * FONT_WIDTH * style.magnification.0)
+            .sum();
+        let height = line
+            .iter()
+            .map(|(_, style)| FONT_HEIGHT * style.magnification.1)
+            .max()
+            .unwrap_or(FONT_HEIGHT);
+        self.roll.grow_to(self.roll_y + height);
+        let mut x = self.justified_x(width);
+        for (text, style) in line {
+            let (mw, mh) = style.magnification;
+            // Characters of a line share the same baseline
+            let top = self.roll_y + height - FONT_HEIGHT * mh;
+            self.roll.draw_text(&text, x, top, style);
+            x += text.len() as u32 * FONT_WIDTH * mw;
+        }
+        self.roll_y += feed.max(height);
+        self.roll.grow_to(self.roll_y);
+    }
+
+    fn print_page(&mut self) {
+        if let Some(page) = self.page.take() {
+            self.roll
+                .blit(&page.canvas, page.area.0, self.roll_y + page.area.1);
+            self.roll_y = self.roll.height;
+        }
+    }
+
+    fn cut(&mut self) {
+        if self.page.is_some() {
+            return;
+        }
+        self.flush_line(0);
+        self.finish_ticket();
+    }
+
+    fn finish_ticket(&mut self) {
+        self.flush_line(0);
+        if self.roll_y == 0 {
+            return;
+        }
+        let roll = std::mem::replace(&mut self.roll, Canvas::new(self.paper_width, 0));
+        self.roll_y = 0;
+        let ticket = roll.into_image();
+        self.printed += 1;
+        match &self.output_dir {
+            Some(dir) => {
+                let path = dir.join(format!("ticket-{:04}.png", self.printed));
+                match ticket.save(&path) {
+                    Ok(_) => log::info!("Printed {}", path.display()),
+                    Err(e) => log::error!("Unable to save {}: {}", path.display(), e),
+                }
+            }
+            None => self.tickets.push(ticket),
+        }
+    }
+}
+
+impl Write for VirtualPrinter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.feed(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for VirtualPrinter {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.replies.pop_front() {
+                Some(byte) => {
+                    buf[read] = byte;
+                    read += 1;
+                }
+                None => break,
+            }
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        escpos::{Command, PaperState, StatusReport},
+        job::PrintJob,
+        printer::{PrinterOptions, PrinterStatus},
+    };
+
+    fn ink(ticket: &GrayImage, x: std::ops::Range<u32>, y: std::ops::Range<u32>) -> usize {
+        y.flat_map(|y| x.clone().map(move |x| (x, y)))
+            .filter(|(x, y)| ticket.get_pixel(*x, *y)[0] == 0)
+            .count()
+    }
+
+    #[test]
+    fn heart_page() {
+        let icon = Bitmap {
+            width: 256,
+            height: 256,
+            data: vec![0xFF; 32 * 256],
+        };
+        // Top half black
+        let heart = Bitmap {
+            width: 256,
+            height: 256,
+            data: [vec![0xFF; 32 * 128], vec![0x00; 32 * 128]].concat(),
+        };
+        let mut job = PrintJob::new(PrinterOptions::default());
+        job.page(576, 656)
+            .font_size(4)
+            .unwrap()
+            .position(216, 184)
+            .text("Ada");
+        job.bitmap_at(304, 240, &icon)
+            .unwrap()
+            .bitmap_at(16, 240, &heart)
+            .unwrap()
+            .print_page()
+            .cut();
+
+        let tickets = VirtualPrinter::render(PAPER_WIDTH, &job.encode());
+        assert_eq!(tickets.len(), 1);
+        let ticket = &tickets[0];
+        // Page, 40 dots of feed, the footer line within 175 dots of feed
+        assert_eq!(ticket.dimensions(), (576, 656 + 40 + 175));
+
+        assert_eq!(ink(ticket, 304..560, 240..496), 256 * 256);
+        assert_eq!(ink(ticket, 16..272, 240..368), 256 * 128);
+        assert_eq!(ink(ticket, 16..272, 368..496), 0);
+        // 4 times magnified text sitting on its baseline
+        assert!(ink(ticket, 216..360, 88..184) > 0);
+        assert_eq!(ink(ticket, 0..576, 0..88), 0);
+        assert_eq!(ink(ticket, 0..576, 184..240), 0);
+        assert_eq!(ink(ticket, 0..576, 496..696), 0);
+        assert!(ink(ticket, 0..576, 696..720) > 0);
+        assert_eq!(ink(ticket, 0..576, 720..871), 0);
+    }
+
+    #[test]
+    fn page_area_is_clipped() {
+        let mut data = Command::SelectPageMode.to_bytes();
+        data.extend(
+            Command::PrintArea {
+                x: 500,
+                y: 0,
+                width: u16::MAX,
+                height: u16::MAX,
+            }
+            .to_bytes(),
+        );
+        data.extend(Command::PrintPage.to_bytes());
+
+        let tickets = VirtualPrinter::render(PAPER_WIDTH, &data);
+        assert_eq!(tickets[0].dimensions(), (PAPER_WIDTH, MAX_PAGE_HEIGHT));
+    }
+
+    fn status(status: VirtualStatus) -> (StatusReport, PrinterStatus) {
+        let mut printer = VirtualPrinter::new(PAPER_WIDTH, None);
+        printer.status = status;
+        for n in StatusReport::REQUESTS {
+            printer
+                .write_all(&Command::StatusRequest(n).to_bytes())
+                .unwrap();
+        }
+        let mut answers = [0; 4];
+        printer.read_exact(&mut answers).unwrap();
+        assert!(answers.into_iter().all(StatusReport::is_valid_byte));
+        assert_eq!(printer.read(&mut [0]).unwrap(), 0);
+        let report = StatusReport::decode(answers[0], answers[1], answers[2], answers[3]);
+        (report, PrinterStatus::from(&report))
+    }
+
+    #[test]
+    fn status_replies() {
+        let (report, printer_status) = status(VirtualStatus::default());
+        assert!(report.online);
+        assert_eq!(printer_status, PrinterStatus::Ok);
+
+        let (report, printer_status) = status(VirtualStatus {
+            paper_near_end: true,
+            ..Default::default()
+        });
+        assert!(report.online);
+        assert_eq!(report.paper, PaperState::NearEnd);
+        assert_eq!(printer_status, PrinterStatus::PaperNearEnd);
+
+        let (report, printer_status) = status(VirtualStatus {
+            paper_out: true,
+            ..Default::default()
+        });
+        assert!(!report.online);
+        assert_eq!(report.paper, PaperState::Out);
+        assert_eq!(printer_status, PrinterStatus::NoPaper);
+
+        let (report, printer_status) = status(VirtualStatus {
+            cover_open: true,
+            ..Default::default()
+        });
+        assert!(!report.online && report.cover_open);
+        assert_eq!(printer_status, PrinterStatus::CoverOpen);
+
+        let (report, printer_status) = status(VirtualStatus {
+            cutter_error: true,
+            ..Default::default()
+        });
+        assert!(!report.online && report.cutter_error);
+        assert_eq!(printer_status, PrinterStatus::CutterError);
+    }
+}
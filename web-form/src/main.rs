@@ -1,4 +1,7 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use axum::{extract::State, http::StatusCode, routing::get, Form, Router};
 use base64::prelude::*;
@@ -256,6 +259,14 @@ async fn post_root(
 
     let api: Api<Job> = Api::default_namespaced(client.client.clone());
 
+    // Fixed for the whole Job, so that retries and pod restarts never print twice
+    let idempotency_key = format!(
+        "web-{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos())
+    );
+
     let curl_command = vec![
         "curl".to_string(),
         "-X".to_string(),
@@ -268,6 +279,8 @@ async fn post_root(
         ),
         "-H".to_string(),
         "'Content-Type: application/json'".to_string(),
+        "-H".to_string(),
+        format!("'Idempotency-Key: {}'", idempotency_key),
         "-d".to_string(),
         format!(
             "'{}'",